@@ -0,0 +1,67 @@
+// Errors returned by libexfat are classified by category, which determines
+// errno replied to kernel and how loud the error is logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Category {
+    Corruption,
+    NoSpace,
+    Name,
+    Device,
+    Other,
+}
+
+impl Category {
+    const ALL: [Self; 5] = [
+        Self::Corruption,
+        Self::NoSpace,
+        Self::Name,
+        Self::Device,
+        Self::Other,
+    ];
+}
+
+// libexfat reports on-disk inconsistency (bad cluster, invalid entry, etc)
+// as EIO after logging details, whereas I/O errors on the device are
+// returned as they are.
+#[cfg(target_os = "linux")]
+const ECORRUPTED: nix::errno::Errno = nix::errno::Errno::EUCLEAN; // EFSCORRUPTED
+#[cfg(not(target_os = "linux"))] // FreeBSD
+const ECORRUPTED: nix::errno::Errno = nix::errno::Errno::EIO;
+
+pub(crate) fn classify(e: &libexfat::Error) -> (Category, nix::errno::Errno) {
+    match e {
+        libexfat::Error::Errno(e) => match e {
+            nix::errno::Errno::EIO => (Category::Corruption, ECORRUPTED),
+            nix::errno::Errno::ENOSPC => (Category::NoSpace, *e),
+            nix::errno::Errno::ENAMETOOLONG | nix::errno::Errno::EILSEQ => (Category::Name, *e),
+            _ => (Category::Other, *e),
+        },
+        libexfat::Error::Error(e) => (
+            Category::Device,
+            libfs::os::error2errno(e).unwrap_or(nix::errno::Errno::EIO),
+        ),
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ErrorCount {
+    count: [u64; Category::ALL.len()],
+}
+
+impl ErrorCount {
+    pub(crate) fn add(&mut self, cat: Category) {
+        self.count[cat as usize] += 1;
+    }
+
+    pub(crate) fn get(&self, cat: Category) -> u64 {
+        self.count[cat as usize]
+    }
+
+    // Logs counts of categories other than Other, e.g. on unmount.
+    pub(crate) fn log(&self) {
+        for cat in Category::ALL {
+            if cat != Category::Other && self.get(cat) > 0 {
+                log::warn!("{cat:?} errors {}", self.get(cat));
+            }
+        }
+    }
+}
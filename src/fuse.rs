@@ -1,5 +1,6 @@
 use byteorder::ByteOrder;
 
+// for nids known to be referenced, e.g. by handle or lookup
 macro_rules! get_node {
     ($ef:expr, $nid:expr) => {
         $ef.get_node($nid).unwrap()
@@ -22,32 +23,647 @@ macro_rules! debug_req {
 
 // libexfat (both relan/exfat and Rust) isn't thread-safe.
 // relan/exfat uses libfuse in single-thread mode (-s option).
+//
+// Finer-grained (per-node or reader/writer) locking doesn't buy anything here.
+// fuser::Session dispatches requests one at a time from a single thread with
+// &mut self, so callbacks never run concurrently in the first place.
+// Even "read-only" callbacks mutate libexfat state, e.g. lookup and readdir
+// get/put node references, and pread updates the node's cluster cache
+// (fptr_index / fptr_cluster), so libexfat would need to be made thread-safe
+// before requests could be dispatched in parallel.
 static MTX: std::sync::LazyLock<std::sync::Mutex<i32>> =
     std::sync::LazyLock::new(|| std::sync::Mutex::new(0));
 
+// errors on destroy, checked by main for exit status after session ends
+pub(crate) static DESTROY_ERRORS: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
 macro_rules! mtx_lock {
     ($mtx:expr) => {
         $mtx.lock().unwrap()
     };
 }
 
-const TTL: std::time::Duration = std::time::Duration::from_secs(1);
+#[cfg(target_os = "linux")]
+const ENOATTR: i32 = libc::ENODATA;
+#[cfg(not(target_os = "linux"))] // FreeBSD
+const ENOATTR: i32 = libc::ENOATTR;
+
+// same values on Linux and FreeBSD
+const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+
+// renameat2(2) flags, only Linux kernel passes them
+const RENAME_NOREPLACE: u32 = 0x01;
+
+// Virtual xattrs exposing exFAT specific metadata.
+// Only attributes is writable and listed, others can be read by name.
+// Raw timestamps aren't exposed, as libexfat converts them to unix time
+// (dropping 10ms and UTC offset) on load.
+const XATTR_ATTRIBUTES: &str = "user.exfat.attributes";
+const XATTR_START_CLUSTER: &str = "user.exfat.start_cluster";
+const XATTR_CONTIGUOUS: &str = "user.exfat.contiguous"; // NoFatChain
+const XATTR_VALID_SIZE: &str = "user.exfat.valid_size";
+
+const XATTR_LIST: [&str; 4] = [
+    XATTR_ATTRIBUTES,
+    XATTR_START_CLUSTER,
+    XATTR_CONTIGUOUS,
+    XATTR_VALID_SIZE,
+];
+
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    let len = data.len().try_into().unwrap();
+    if size == 0 {
+        reply.size(len);
+    } else if size < len {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+// Notifications to kernel, sent by notify() running in a separate thread.
+pub(crate) enum Notify {
+    InvalInode(u64),
+    InvalEntry(u64, std::ffi::OsString),
+}
+
+pub(crate) fn notify(notifier: &fuser::Notifier, rx: &std::sync::mpsc::Receiver<Notify>) {
+    while let Ok(x) = rx.recv() {
+        let result = match x {
+            Notify::InvalInode(nid) => {
+                log::debug!("inval_inode nid {nid}");
+                notifier.inval_inode(nid, 0, 0) // whole page cache and attributes
+            }
+            Notify::InvalEntry(dnid, name) => {
+                log::debug!("inval_entry dnid {dnid} name {name:?}");
+                notifier.inval_entry(dnid, &name)
+            }
+        };
+        // ENOENT if kernel doesn't have it cached
+        if let Err(e) = result {
+            log::debug!("{e}");
+        }
+    }
+}
 
-fn stat2attr(st: &libexfat::exfat::Stat) -> fuser::FileAttr {
-    let attr = crate::util::stat2attr(st);
-    log::debug!("{attr:?}");
-    attr
+// An unlinked file which is still open is renamed to a hidden name,
+// and then actually unlinked on last release.
+fn get_hidden_name(nid: u64) -> String {
+    format!(".exfat_hidden_{nid:016x}")
 }
 
-#[allow(clippy::needless_pass_by_value)]
-fn e2i(e: libexfat::Error) -> i32 {
-    (match e {
-        libexfat::Error::Errno(e) => e,
-        libexfat::Error::Error(e) => match libfs::os::error2errno(&e) {
+impl crate::ExfatFuse {
+    // Converts libexfat error to errno, logged with the operation and nid.
+    fn e2i(&mut self, e: libexfat::Error, op: &str, nid: u64) -> i32 {
+        let (cat, errno) = crate::error::classify(&e);
+        self.errors.add(cat);
+        if cat == crate::error::Category::Corruption {
+            self.handle_corruption();
+        }
+        match cat {
+            crate::error::Category::Corruption | crate::error::Category::Device => {
+                log::error!("{op} nid {nid}: {cat:?}: {e}");
+            }
+            crate::error::Category::NoSpace | crate::error::Category::Name => {
+                log::warn!("{op} nid {nid}: {cat:?}: {e}");
+            }
+            crate::error::Category::Other => log::debug!("{op} nid {nid}: {e}"),
+        }
+        errno as i32
+    }
+
+    // similar to errors= mount option of Linux kernel file systems
+    fn handle_corruption(&mut self) {
+        match self.opt.errors {
+            crate::ErrorPolicy::Continue => (),
+            crate::ErrorPolicy::RemountRo => {
+                if !self.ro {
+                    log::error!("switching to read-only");
+                    self.ro = true;
+                }
+            }
+            // Exiting closes the FUSE connection, which fails requests in
+            // flight and doesn't wait for open files unlike wait_signal.
+            // Nothing is written back, so the super block is left dirty.
+            crate::ErrorPolicy::Panic => {
+                log::error!("unmounting");
+                self.ro = true;
+                if let Err(e) = crate::util::unmount_lazy(&self.mntpt) {
+                    log::error!("{e}");
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn stat2attr(&self, st: &libexfat::exfat::Stat) -> Result<fuser::FileAttr, i32> {
+        let Some(mut attr) = crate::util::stat2attr(st) else {
+            log::error!("nid {} has invalid mode {:o}", st.st_ino, st.st_mode);
+            return Err(libc::EIO);
+        };
+        if let Some(node) = self.ef.get_node(st.st_ino) {
+            if !node.is_directory() {
+                attr.perm = crate::util::attrib2perm(node.get_attrib(), attr.perm);
+            }
+        }
+        log::debug!("{attr:?}");
+        Ok(attr)
+    }
+
+    fn get_xattr(&self, nid: u64, name: &std::ffi::OsStr) -> Option<String> {
+        let node = self.ef.get_node(nid)?;
+        Some(if name == XATTR_ATTRIBUTES {
+            format!("{:#06x}", node.get_attrib())
+        } else if name == XATTR_START_CLUSTER {
+            node.get_start_cluster().to_string()
+        } else if name == XATTR_CONTIGUOUS {
+            u8::from(node.is_contiguous()).to_string()
+        } else if name == XATTR_VALID_SIZE {
+            node.get_valid_size().to_string()
+        } else {
+            return None;
+        })
+    }
+
+    // exFAT has no sparse files, but the region between ValidDataLength and
+    // the file size reads as zeros, i.e. it's a hole at the end of the file.
+    fn punch_hole(&mut self, nid: u64, offset: u64, end: u64) -> Result<(), libexfat::Error> {
+        let valid_size = get_node!(self.ef, nid).get_valid_size();
+        if offset >= valid_size {
+            return Ok(()); // already a hole
+        }
+        if end < valid_size {
+            return Err(libexfat::Error::Errno(nix::errno::Errno::EOPNOTSUPP));
+        }
+        // Lower ValidDataLength to offset by shrinking and then extending
+        // the file without erasing, which keeps the file size.
+        let size = self.ef.stat(nid)?.st_size;
+        get_node_mut!(self.ef, nid).get();
+        let mut result = self.ef.truncate(nid, offset, true);
+        if result.is_ok() {
+            result = self.ef.truncate(nid, size, false);
+        }
+        self.inval_inode(nid);
+        if let Err(e) = self.ef.flush_node(nid) {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+        get_node_mut!(self.ef, nid).put();
+        result
+    }
+
+    fn copy_range(
+        &mut self,
+        nid_in: u64,
+        offset_in: u64,
+        nid_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<u64, libexfat::Error> {
+        let st_in = self.ef.stat(nid_in)?;
+        if offset_in >= st_in.st_size {
+            return Ok(0);
+        }
+        let len = len.min(st_in.st_size - offset_in);
+        let end_out = offset_out + len;
+
+        // extend the output file once, the same way pwrite would
+        let size_out = self.ef.stat(nid_out)?.st_size;
+        if offset_out > size_out {
+            self.ef.truncate(nid_out, offset_out, true)?;
+        }
+        if end_out > size_out.max(offset_out) {
+            self.ef.truncate(nid_out, end_out, false)?;
+        }
+
+        let mut buf = vec![0; st_in.st_blksize.try_into().unwrap()]; // cluster size
+        let mut total = 0;
+        let mut result = Ok(());
+        while total < len {
+            let n = buf
+                .len()
+                .min((len - total).try_into().unwrap_or(usize::MAX));
+            let bytes = match self.ef.pread(nid_in, &mut buf[..n], offset_in + total) {
+                Ok(v) => v,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            if bytes == 0 {
+                break;
+            }
+            let bytes = bytes.try_into().unwrap();
+            if let Err(e) = self.ef.pwrite(nid_out, &buf[..bytes], offset_out + total) {
+                result = Err(e);
+                break;
+            }
+            total += u64::try_from(bytes).unwrap();
+        }
+
+        // don't leave the zero tail extended above, either on error
+        // (original size) or on short copy (up to what's been copied)
+        let size = if result.is_err() {
+            size_out
+        } else {
+            size_out.max(offset_out + total)
+        };
+        if end_out > size {
+            if let Err(e) = self.ef.truncate(nid_out, size, true) {
+                log::error!("failed to truncate nid {nid_out} to {size}: {e}");
+            }
+        }
+        result.map(|()| total)
+    }
+
+    fn set_attrib(&mut self, nid: u64, attrib: u16) -> Result<(), libexfat::Error> {
+        if get_node!(self.ef, nid).get_attrib() == attrib {
+            return Ok(());
+        }
+        get_node_mut!(self.ef, nid).get();
+        self.ef.set_attrib(nid, attrib);
+        let result = self.ef.flush_node(nid);
+        get_node_mut!(self.ef, nid).put();
+        result
+    }
+
+    // Negative entry with zero nid is cached by kernel for negative_timeout.
+    // Names are recorded per directory, so that creating a case variant of
+    // one of them can invalidate it, see inval_negatives.
+    fn reply_noent(&mut self, reply: fuser::ReplyEntry, dnid: u64, name: &str) {
+        if self.opt.negative_timeout.is_zero() {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let now = std::time::Instant::now();
+        let m = self.negatives.entry(dnid).or_default();
+        m.retain(|_, t| *t > now);
+        m.insert(name.to_string(), now + self.opt.negative_timeout);
+        reply.entry(
+            &self.opt.negative_timeout,
+            &crate::util::get_negative_attr(),
+            0,
+        );
+    }
+
+    // kernel compares names case-sensitively, so negative entries of
+    // case variants of a created name remain valid unless invalidated
+    fn inval_negatives(&mut self, dnid: u64, name: &str) {
+        let Some(m) = self.negatives.get_mut(&dnid) else {
+            return;
+        };
+        let upper = name.to_uppercase();
+        let now = std::time::Instant::now();
+        let mut v = vec![];
+        m.retain(|k, t| {
+            if *t <= now {
+                false
+            } else if k.to_uppercase() == upper {
+                v.push(k.clone());
+                false
+            } else {
+                true
+            }
+        });
+        if m.is_empty() {
+            self.negatives.remove(&dnid);
+        }
+        for k in &v {
+            if k != name {
+                self.inval_entry(dnid, k);
+            }
+        }
+    }
+
+    fn get_open_flags(&mut self, nid: u64, fh: u64) -> u32 {
+        // O_DIRECT bypasses page cache
+        if self.handles[&fh].is_direct() {
+            return fuser::consts::FOPEN_DIRECT_IO;
+        }
+        match self.opt.cache {
+            crate::CachePolicy::Always => fuser::consts::FOPEN_KEEP_CACHE,
+            crate::CachePolicy::Auto => {
+                if self.update_cache_attr(nid) {
+                    fuser::consts::FOPEN_KEEP_CACHE
+                } else {
+                    0 // kernel drops page cache
+                }
+            }
+            crate::CachePolicy::Never => fuser::consts::FOPEN_DIRECT_IO,
+        }
+    }
+
+    // Returns true if mtime and size are unchanged since last recorded.
+    // Stat comes from libexfat's in-memory node, i.e. as changed via this mount.
+    fn update_cache_attr(&mut self, nid: u64) -> bool {
+        let Ok(st) = self.ef.stat(nid) else {
+            self.cache_attrs.remove(&nid);
+            return false;
+        };
+        let attr = (libfs::time::unix2system(st.st_mtime), st.st_size);
+        self.cache_attrs.insert(nid, attr) == Some(attr)
+    }
+
+    fn decode_name(&self, name: &std::ffi::OsStr) -> Result<String, i32> {
+        let v = crate::name::decode(name, self.opt.iocharset, self.opt.badchars);
+        if v.is_err() {
+            log::debug!("{name:?} can't be used as exFAT name");
+        }
+        v
+    }
+
+    fn encode_name(&self, name: &str) -> Option<std::ffi::OsString> {
+        crate::name::encode(name, self.opt.iocharset, self.opt.badchars)
+    }
+
+    fn inval_inode(&self, nid: u64) {
+        if self.notify.send(Notify::InvalInode(nid)).is_err() {
+            // ignore this error
+        }
+    }
+
+    fn inval_entry(&self, dnid: u64, name: &str) {
+        let Some(name) = self.encode_name(name) else {
+            return; // kernel can't have it cached
+        };
+        if self.notify.send(Notify::InvalEntry(dnid, name)).is_err() {
+            // ignore this error
+        }
+    }
+
+    fn get_child_names(&mut self, dnid: u64) -> Result<Vec<String>, libexfat::Error> {
+        let mut c = self.ef.opendir_cursor(dnid)?;
+        let mut v = vec![];
+        loop {
+            let nid = match self.ef.readdir_cursor(&mut c) {
+                Ok(v) => v,
+                Err(e) => {
+                    if let libexfat::Error::Errno(e) = e {
+                        if e == nix::errno::Errno::ENOENT {
+                            break;
+                        }
+                    }
+                    self.ef.closedir_cursor(c);
+                    return Err(e);
+                }
+            };
+            v.push(get_node!(self.ef, nid).get_name().to_string());
+            get_node_mut!(self.ef, nid).put();
+        }
+        self.ef.closedir_cursor(c);
+        Ok(v)
+    }
+
+    // Hidden files keep a directory non-empty, so if a directory only has
+    // hidden files, they're moved to the root directory till last release.
+    fn move_hidden_children(&mut self, dnid: u64) -> Result<(), libexfat::Error> {
+        let hidden = self
+            .unlinked
+            .iter()
+            .map(|&nid| get_hidden_name(nid))
+            .collect::<std::collections::HashSet<_>>();
+        let names = self.get_child_names(dnid)?;
+        if names.is_empty() || !names.iter().all(|x| hidden.contains(x)) {
+            return Ok(());
+        }
+        let mut result = Ok(());
+        for name in &names {
+            log::debug!("dnid {dnid} name {name} -> root");
+            result = self.ef.rename_at(dnid, name, fuser::FUSE_ROOT_ID, name);
+            if result.is_err() {
+                break;
+            }
+        }
+        // both directories changed even if some renames failed
+        self.invalidate_cursors(dnid);
+        self.invalidate_cursors(fuser::FUSE_ROOT_ID);
+        result
+    }
+
+    // libexfat looks up names folded through the volume's up-case table, so
+    // a name may find a node whose on-disk name differs in case.
+    // Returns the on-disk name in that case.
+    fn get_case_alias(&self, nid: u64, name: &str) -> Option<String> {
+        let v = get_node!(self.ef, nid).get_name();
+        (v != name).then(|| v.to_string())
+    }
+
+    // exFAT names are unique within a directory case-insensitively, hence
+    // a name differing only in case from an existing one can't be created.
+    fn check_exist(&mut self, dnid: u64, name: &str, op: &str) -> Result<(), i32> {
+        let nid = match self.ef.lookup_at(dnid, name) {
+            Ok(v) => v,
+            Err(libexfat::Error::Errno(nix::errno::Errno::ENOENT)) => return Ok(()),
+            Err(e) => return Err(self.e2i(e, op, dnid)),
+        };
+        if let Some(v) = self.get_case_alias(nid, name) {
+            log::debug!("dnid {dnid} name {name} exists as {v}");
+        }
+        get_node_mut!(self.ef, nid).put();
+        Err(libc::EEXIST)
+    }
+
+    fn hide_node(&mut self, dnid: u64, name: &str, nid: u64) -> Result<(), libexfat::Error> {
+        let hname = get_hidden_name(nid);
+        log::debug!("dnid {dnid} name {name} nid {nid} -> {hname}");
+        self.ef.rename_at(dnid, name, dnid, &hname)?;
+        self.invalidate_cursors(dnid);
+        assert!(self.unlinked.insert(nid));
+        Ok(())
+    }
+
+    // Reverts hide_node, e.g. when the operation which hid nid failed.
+    fn unhide_node(&mut self, dnid: u64, name: &str, nid: u64) -> Result<(), libexfat::Error> {
+        let hname = get_hidden_name(nid);
+        log::debug!("dnid {dnid} name {hname} nid {nid} -> {name}");
+        self.ef.rename_at(dnid, &hname, dnid, name)?;
+        self.invalidate_cursors(dnid);
+        self.unlinked.remove(&nid);
+        Ok(())
+    }
+
+    // Drops the reference taken on open, and unlinks a hidden file on its
+    // last release.
+    fn put_opened_node(&mut self, nid: u64) -> Result<(), libexfat::Error> {
+        if self.unlinked.contains(&nid) && !self.is_open(nid) {
+            self.unlinked.remove(&nid);
+            self.cache_attrs.remove(&nid);
+            // hidden file is left for fsck after corruption
+            if self.ro {
+                get_node_mut!(self.ef, nid).put();
+                return Ok(());
+            }
+            let pnid = get_node!(self.ef, nid).get_pnid();
+            // unlink consumes the reference taken on open
+            if let Err(e) = self.ef.unlink(nid) {
+                get_node_mut!(self.ef, nid).put();
+                return Err(e);
+            }
+            // hidden name is gone from readdir of the parent
+            self.invalidate_cursors(pnid);
+        } else {
+            get_node_mut!(self.ef, nid).put();
+        }
+        Ok(())
+    }
+
+    fn opendir_cursor(&mut self, dnid: u64) -> Result<crate::handle::Dir, libexfat::Error> {
+        Ok(crate::handle::Dir {
+            cursor: Some(self.ef.opendir_cursor(dnid)?),
+            next: 3, // 1 and 2 are for "." and ".."
+            pending: None,
+            seen: std::collections::HashSet::new(),
+        })
+    }
+
+    fn closedir_cursor(&mut self, dir: crate::handle::Dir) {
+        if let Some(nid) = dir.pending {
+            get_node_mut!(self.ef, nid).put();
+        }
+        if let Some(cursor) = dir.cursor {
+            self.ef.closedir_cursor(cursor);
+        }
+    }
+
+    // Cursors of a modified directory are closed, and the next readdir
+    // reopens a cursor and skips entries already replied, so that neither
+    // removed nor inserted entries shift the rest.
+    fn invalidate_cursors(&mut self, dnid: u64) {
+        for h in self.handles.values_mut().filter(|h| h.get_nid() == dnid) {
+            let Some(dir) = h.get_dir_mut() else {
+                continue;
+            };
+            if let Some(cursor) = dir.cursor.take() {
+                self.ef.closedir_cursor(cursor);
+            }
+            if let Some(nid) = dir.pending.take() {
+                get_node_mut!(self.ef, nid).put();
+            }
+        }
+    }
+
+    // Common part of readdir and readdirplus.
+    // add returns true if the reply buffer is full.
+    fn fill_dir<F>(&mut self, dnid: u64, fh: u64, offset: i64, mut add: F) -> Result<(), i32>
+    where
+        F: FnMut(u64, i64, &fuser::FileAttr, &std::ffi::OsStr) -> bool,
+    {
+        if self.get_handle(dnid, fh).is_none() {
+            return Err(libc::EBADF);
+        }
+        let Some(dnode) = self.ef.get_node(dnid) else {
+            return Err(libc::ENOENT);
+        };
+        if !dnode.is_directory() {
+            return Err(libc::ENOTDIR);
+        }
+
+        // kernel ignores attributes of "." and ".." in readdirplus
+        let pnid = dnode.get_pnid();
+        let st = self
+            .ef
+            .stat(dnid)
+            .map_err(|e| self.e2i(e, "readdir", dnid))?;
+        let mut attr = self.stat2attr(&st)?;
+        if offset < 1 && add(dnid, 1, &attr, std::ffi::OsStr::new(".")) {
+            return Ok(());
+        }
+        attr.ino = pnid;
+        if offset < 2 && add(pnid, 2, &attr, std::ffi::OsStr::new("..")) {
+            return Ok(());
+        }
+
+        // resume from the cursor if offset follows the last entry replied
+        // otherwise (e.g. rewinddir) restart and skip entries up to offset
+        let mut dir = match self.handles.get_mut(&fh).unwrap().take_dir() {
+            Some(v) if v.next == offset.max(2) + 1 => v,
+            v => {
+                if let Some(v) = v {
+                    self.closedir_cursor(v);
+                }
+                self.opendir_cursor(dnid)
+                    .map_err(|e| self.e2i(e, "readdir", dnid))?
+            }
+        };
+        let mut cursor = match dir.cursor.take() {
             Some(v) => v,
-            None => nix::errno::Errno::EINVAL,
-        },
-    }) as i32
+            None => match self.ef.opendir_cursor(dnid) {
+                Ok(v) => v,
+                Err(e) => {
+                    self.closedir_cursor(dir);
+                    return Err(self.e2i(e, "readdir", dnid));
+                }
+            },
+        };
+        loop {
+            let nid = if let Some(nid) = dir.pending.take() {
+                nid
+            } else {
+                match self.ef.readdir_cursor(&mut cursor) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        if let libexfat::Error::Errno(e) = e {
+                            if e == nix::errno::Errno::ENOENT {
+                                break;
+                            }
+                        }
+                        self.ef.closedir_cursor(cursor);
+                        self.closedir_cursor(dir);
+                        return Err(self.e2i(e, "readdir", dnid));
+                    }
+                }
+            };
+            if self.unlinked.contains(&nid) || dir.seen.contains(&nid) {
+                get_node_mut!(self.ef, nid).put();
+                continue;
+            }
+            if dir.next > offset {
+                let node = get_node!(self.ef, nid);
+                let Some(name) = self.encode_name(node.get_name()) else {
+                    log::warn!(
+                        "nid {nid} name {} not representable in {:?}",
+                        node.get_name(),
+                        self.opt.iocharset
+                    );
+                    get_node_mut!(self.ef, nid).put();
+                    dir.seen.insert(nid);
+                    dir.next += 1;
+                    continue;
+                };
+                let st = match self.ef.stat(nid) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        get_node_mut!(self.ef, nid).put();
+                        self.ef.closedir_cursor(cursor);
+                        self.closedir_cursor(dir);
+                        return Err(self.e2i(e, "readdir", dnid));
+                    }
+                };
+                let attr = match self.stat2attr(&st) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        get_node_mut!(self.ef, nid).put();
+                        self.ef.closedir_cursor(cursor);
+                        self.closedir_cursor(dir);
+                        return Err(e);
+                    }
+                };
+                if add(st.st_ino, dir.next, &attr, &name) {
+                    dir.pending = Some(nid); // put on next readdir or releasedir
+                    break;
+                }
+            }
+            get_node_mut!(self.ef, nid).put();
+            dir.seen.insert(nid);
+            dir.next += 1;
+        }
+        dir.cursor = Some(cursor);
+        self.handles.get_mut(&fh).unwrap().set_dir(dir);
+        Ok(())
+    }
 }
 
 impl fuser::Filesystem for crate::ExfatFuse {
@@ -59,9 +675,19 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("config {config:?}");
         let _mtx = mtx_lock!(MTX);
+        // FUSE_FLOCK_LOCKS isn't set, as fuser doesn't pass FUSE_LK_FLOCK to
+        // tell flock(2) locks from POSIX locks, which mustn't conflict with
+        // each other. Kernel handles flock(2) locks locally instead.
+        if let Err(e) = config.add_capabilities(
+            fuser::consts::FUSE_DO_READDIRPLUS
+                | fuser::consts::FUSE_READDIRPLUS_AUTO
+                | fuser::consts::FUSE_POSIX_LOCKS,
+        ) {
+            log::debug!("unsupported capabilities {e:#x}");
+        }
         // mark super block as dirty; failure isn't a big deal
         if let Err(e) = self.ef.soil_super_block() {
-            return Err(e2i(e));
+            return Err(self.e2i(e, "init", fuser::FUSE_ROOT_ID));
         }
         Ok(())
     }
@@ -69,8 +695,52 @@ impl fuser::Filesystem for crate::ExfatFuse {
     fn destroy(&mut self) {
         log::debug!("destroy");
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(self.total_open, 0);
-        self.ef.unmount().unwrap();
+        let mut errors = 0;
+        // handles are left open on lazy unmount (umount -l) or signal
+        if !self.handles.is_empty() {
+            log::warn!("{} handles still open", self.handles.len());
+        }
+        // removed one by one, so that hidden file is unlinked on its last handle
+        let l = self.handles.keys().copied().collect::<Vec<_>>();
+        for fh in l {
+            let mut h = self.handles.remove(&fh).unwrap();
+            let nid = h.get_nid();
+            log::debug!("fh {fh} nid {nid} force released");
+            if let Some(dir) = h.take_dir() {
+                self.closedir_cursor(dir);
+            }
+            if !self.ro {
+                if let Err(e) = self.ef.flush_node(nid) {
+                    log::error!("failed to flush nid {nid}: {e}");
+                    errors += 1;
+                }
+            }
+            if let Err(e) = self.put_opened_node(nid) {
+                log::error!("failed to unlink nid {nid}: {e}");
+                errors += 1;
+            }
+        }
+        self.locks = crate::lock::LockManager::new(); // pending waiters get EIO
+        if self.ro {
+            // unmount would clean super block soiled on init
+            log::error!("leaving file system dirty after corruption");
+            errors += 1;
+        } else {
+            if let Err(e) = self.ef.flush_nodes() {
+                log::error!("failed to flush nodes: {e}");
+                errors += 1;
+            }
+            // unmount anyway, which flushes and cleans super block if possible
+            if let Err(e) = self.ef.unmount() {
+                log::error!("failed to unmount: {e}");
+                errors += 1;
+            }
+        }
+        if errors > 0 {
+            log::error!("{errors} errors on unmount");
+        }
+        self.errors.log();
+        DESTROY_ERRORS.fetch_add(errors, std::sync::atomic::Ordering::Relaxed);
     }
 
     fn lookup(
@@ -83,27 +753,50 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("dnid {dnid} name {name:?}");
         let _mtx = mtx_lock!(MTX);
-        let Some(name) = name.to_str() else {
-            reply.error(libc::EINVAL);
-            return;
+        let name = match self.decode_name(name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        let nid = match self.ef.lookup_at(dnid, name) {
+        let nid = match self.ef.lookup_at(dnid, &name) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                let e = self.e2i(e, "lookup", dnid);
+                if e == libc::ENOENT {
+                    self.reply_noent(reply, dnid, &name);
+                } else {
+                    reply.error(e);
+                }
                 return;
             }
         };
+        if self.unlinked.contains(&nid) {
+            get_node_mut!(self.ef, nid).put();
+            self.reply_noent(reply, dnid, &name);
+            return;
+        }
         let st = match self.ef.stat(nid) {
             Ok(v) => v,
             Err(e) => {
                 get_node_mut!(self.ef, nid).put();
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "lookup", dnid));
                 return;
             }
         };
+        // a case variant of the on-disk name isn't cached, so that kernel
+        // has at most one dentry of the node to invalidate
+        let ttl = if self.get_case_alias(nid, &name).is_some() {
+            std::time::Duration::ZERO
+        } else {
+            self.opt.entry_timeout
+        };
         get_node_mut!(self.ef, nid).put();
-        reply.entry(&TTL, &stat2attr(&st), 0);
+        match self.stat2attr(&st) {
+            Ok(v) => reply.entry(&ttl, &v, 0),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn getattr(
@@ -117,16 +810,22 @@ impl fuser::Filesystem for crate::ExfatFuse {
         log::debug!("nid {nid}");
         let _mtx = mtx_lock!(MTX);
         if let Some(fh) = fh {
-            assert_eq!(nid, fh);
+            if self.get_handle(nid, fh).is_none() {
+                reply.error(libc::EBADF);
+                return;
+            }
         }
         let st = match self.ef.stat(nid) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "getattr", nid));
                 return;
             }
         };
-        reply.attr(&TTL, &stat2attr(&st));
+        match self.stat2attr(&st) {
+            Ok(v) => reply.attr(&self.opt.attr_timeout, &v),
+            Err(e) => reply.error(e),
+        }
     }
 
     #[allow(clippy::similar_names)]
@@ -193,28 +892,24 @@ impl fuser::Filesystem for crate::ExfatFuse {
             log::debug!("nid {nid}");
         }
         let _mtx = mtx_lock!(MTX);
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
         if let Some(fh) = fh {
-            assert_eq!(nid, fh);
+            if self.get_handle(nid, fh).is_none() {
+                reply.error(libc::EBADF);
+                return;
+            }
         }
         let mut st = match self.ef.stat(nid) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "setattr", nid));
                 return;
             }
         };
-        if let Some(mode) = mode {
-            let mode_mask =
-                libc::S_IFREG | libc::S_IFDIR | libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO;
-            #[cfg(target_os = "linux")]
-            let valid_mode_mask = mode_mask;
-            #[cfg(not(target_os = "linux"))] // FreeBSD
-            let valid_mode_mask = u32::from(mode_mask);
-            if (mode & !valid_mode_mask) != 0 {
-                reply.error(libc::EPERM);
-                return;
-            }
-        }
+        // validate everything before making any change
         if let Some(uid) = uid {
             if uid != st.st_uid {
                 reply.error(libc::EPERM);
@@ -227,6 +922,27 @@ impl fuser::Filesystem for crate::ExfatFuse {
                 return;
             }
         }
+        if let Some(mode) = mode {
+            let mode_mask =
+                libc::S_IFREG | libc::S_IFDIR | libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO;
+            #[cfg(target_os = "linux")]
+            let valid_mode_mask = mode_mask;
+            #[cfg(not(target_os = "linux"))] // FreeBSD
+            let valid_mode_mask = u32::from(mode_mask);
+            if (mode & !valid_mode_mask) != 0 {
+                reply.error(libc::EPERM);
+                return;
+            }
+            // write bits of regular file map to read-only attribute
+            let node = get_node!(self.ef, nid);
+            if !node.is_directory() {
+                let attrib = crate::util::perm2attrib(node.get_attrib(), mode);
+                if let Err(e) = self.set_attrib(nid, attrib) {
+                    reply.error(self.e2i(e, "setattr", nid));
+                    return;
+                }
+            }
+        }
         if let Some(size) = size {
             get_node_mut!(self.ef, nid).get();
             if let Err(e) = self.ef.truncate(nid, size, true) {
@@ -234,12 +950,12 @@ impl fuser::Filesystem for crate::ExfatFuse {
                     // ignore this error
                 }
                 get_node_mut!(self.ef, nid).put();
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "setattr", nid));
                 return;
             }
             if let Err(e) = self.ef.flush_node(nid) {
                 get_node_mut!(self.ef, nid).put();
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "setattr", nid));
                 return;
             }
             // truncate has updated mtime
@@ -247,26 +963,45 @@ impl fuser::Filesystem for crate::ExfatFuse {
                 Ok(v) => v,
                 Err(e) => {
                     get_node_mut!(self.ef, nid).put();
-                    reply.error(e2i(e));
+                    reply.error(self.e2i(e, "setattr", nid));
                     return;
                 }
             };
             get_node_mut!(self.ef, nid).put();
             st.st_size = size;
         }
-        let mut attr = crate::util::stat2attr(&st);
-        if let Some(atime) = atime {
-            attr.atime = match atime {
-                fuser::TimeOrNow::SpecificTime(v) => v,
-                fuser::TimeOrNow::Now => std::time::SystemTime::now(),
-            };
-        }
-        if let Some(mtime) = mtime {
-            attr.mtime = match mtime {
-                fuser::TimeOrNow::SpecificTime(v) => v,
-                fuser::TimeOrNow::Now => std::time::SystemTime::now(),
+        // after truncate so that explicitly given mtime wins
+        if atime.is_some() || mtime.is_some() {
+            let tv = [
+                crate::util::time2timespec(atime, libfs::time::unix2system(st.st_atime)),
+                crate::util::time2timespec(mtime, libfs::time::unix2system(st.st_mtime)),
+            ];
+            get_node_mut!(self.ef, nid).get();
+            self.ef.utimes(nid, &tv);
+            if let Err(e) = self.ef.flush_node(nid) {
+                get_node_mut!(self.ef, nid).put();
+                reply.error(self.e2i(e, "setattr", nid));
+                return;
+            }
+            // reply what's been stored on disk
+            st = match self.ef.stat(nid) {
+                Ok(v) => v,
+                Err(e) => {
+                    get_node_mut!(self.ef, nid).put();
+                    reply.error(self.e2i(e, "setattr", nid));
+                    return;
+                }
             };
+            get_node_mut!(self.ef, nid).put();
         }
+        let mut attr = match self.stat2attr(&st) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+        // ctime and crtime aren't stored by libexfat
         if let Some(ctime) = ctime {
             attr.ctime = ctime;
         }
@@ -274,7 +1009,7 @@ impl fuser::Filesystem for crate::ExfatFuse {
             attr.crtime = crtime;
         }
         log::debug!("{attr:?}");
-        reply.attr(&TTL, &attr);
+        reply.attr(&self.opt.attr_timeout, &attr);
     }
 
     fn mknod(
@@ -290,25 +1025,41 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("dnid {dnid} name {name:?} mode {mode:#o} umask {umask:#o} rdev {rdev}");
         let _mtx = mtx_lock!(MTX);
-        let Some(name) = name.to_str() else {
-            reply.error(libc::EINVAL);
+        if self.ro {
+            reply.error(libc::EROFS);
             return;
+        }
+        let name = match self.decode_name(name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        let nid = match self.ef.mknod_at(dnid, name) {
+        if let Err(e) = self.check_exist(dnid, &name, "mknod") {
+            reply.error(e);
+            return;
+        }
+        let nid = match self.ef.mknod_at(dnid, &name) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "mknod", dnid));
                 return;
             }
         };
+        self.invalidate_cursors(dnid);
+        self.inval_negatives(dnid, &name);
         let st = match self.ef.stat(nid) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "mknod", dnid));
                 return;
             }
         };
-        reply.entry(&TTL, &stat2attr(&st), 0);
+        match self.stat2attr(&st) {
+            Ok(v) => reply.entry(&self.opt.entry_timeout, &v, 0),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn mkdir(
@@ -323,25 +1074,41 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("dnid {dnid} name {name:?} mode {mode:#o} umask {umask:#o}");
         let _mtx = mtx_lock!(MTX);
-        let Some(name) = name.to_str() else {
-            reply.error(libc::EINVAL);
+        if self.ro {
+            reply.error(libc::EROFS);
             return;
+        }
+        let name = match self.decode_name(name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        let nid = match self.ef.mkdir_at(dnid, name) {
+        if let Err(e) = self.check_exist(dnid, &name, "mkdir") {
+            reply.error(e);
+            return;
+        }
+        let nid = match self.ef.mkdir_at(dnid, &name) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "mkdir", dnid));
                 return;
             }
         };
+        self.invalidate_cursors(dnid);
+        self.inval_negatives(dnid, &name);
         let st = match self.ef.stat(nid) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "mkdir", dnid));
                 return;
             }
         };
-        reply.entry(&TTL, &stat2attr(&st), 0);
+        match self.stat2attr(&st) {
+            Ok(v) => reply.entry(&self.opt.entry_timeout, &v, 0),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn unlink(
@@ -354,24 +1121,55 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("dnid {dnid} name {name:?}");
         let _mtx = mtx_lock!(MTX);
-        let Some(name) = name.to_str() else {
-            reply.error(libc::EINVAL);
+        if self.ro {
+            reply.error(libc::EROFS);
             return;
+        }
+        let name = match self.decode_name(name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        let nid = match self.ef.lookup_at(dnid, name) {
+        let nid = match self.ef.lookup_at(dnid, &name) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "unlink", dnid));
                 return;
             }
         };
+        if self.unlinked.contains(&nid) {
+            get_node_mut!(self.ef, nid).put();
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let alias = self.get_case_alias(nid, &name);
+        if self.is_open(nid) {
+            get_node_mut!(self.ef, nid).put();
+            if let Err(e) = self.hide_node(dnid, &name, nid) {
+                reply.error(self.e2i(e, "unlink", dnid));
+                return;
+            }
+            if let Some(v) = alias {
+                self.inval_entry(dnid, &v);
+            }
+            reply.ok();
+            return;
+        }
         if let Err(e) = self.ef.unlink(nid) {
             if let Some(node) = self.ef.get_node_mut(nid) {
                 node.put();
             }
-            reply.error(e2i(e));
+            reply.error(self.e2i(e, "unlink", dnid));
             return;
         }
+        self.cache_attrs.remove(&nid);
+        self.invalidate_cursors(dnid);
+        // kernel only invalidates dentry of the name given
+        if let Some(v) = alias {
+            self.inval_entry(dnid, &v);
+        }
         reply.ok();
     }
 
@@ -385,24 +1183,42 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("dnid {dnid} name {name:?}");
         let _mtx = mtx_lock!(MTX);
-        let Some(name) = name.to_str() else {
-            reply.error(libc::EINVAL);
+        if self.ro {
+            reply.error(libc::EROFS);
             return;
+        }
+        let name = match self.decode_name(name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        let nid = match self.ef.lookup_at(dnid, name) {
+        let nid = match self.ef.lookup_at(dnid, &name) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "rmdir", dnid));
                 return;
             }
         };
+        let alias = self.get_case_alias(nid, &name);
+        if let Err(e) = self.move_hidden_children(nid) {
+            get_node_mut!(self.ef, nid).put();
+            reply.error(self.e2i(e, "rmdir", dnid));
+            return;
+        }
         if let Err(e) = self.ef.rmdir(nid) {
             if let Some(node) = self.ef.get_node_mut(nid) {
                 node.put();
             }
-            reply.error(e2i(e));
+            reply.error(self.e2i(e, "rmdir", dnid));
             return;
         }
+        self.cache_attrs.remove(&nid);
+        self.invalidate_cursors(dnid);
+        if let Some(v) = alias {
+            self.inval_entry(dnid, &v);
+        }
         reply.ok();
     }
 
@@ -422,18 +1238,98 @@ impl fuser::Filesystem for crate::ExfatFuse {
             new_dnid {new_dnid} new_name {new_name:?} flags {flags:#x}"
         );
         let _mtx = mtx_lock!(MTX);
-        let Some(old_name) = old_name.to_str() else {
-            reply.error(libc::EINVAL);
+        if self.ro {
+            reply.error(libc::EROFS);
             return;
-        };
-        let Some(new_name) = new_name.to_str() else {
+        }
+        // RENAME_EXCHANGE can't be atomic on disk, as libexfat can't swap
+        // directory entry sets in place. RENAME_WHITEOUT is for overlay
+        // file systems.
+        if (flags & !RENAME_NOREPLACE) != 0 {
             reply.error(libc::EINVAL);
             return;
+        }
+        let old_name = match self.decode_name(old_name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        if let Err(e) = self.ef.rename_at(old_dnid, old_name, new_dnid, new_name) {
-            reply.error(e2i(e));
+        let new_name = match self.decode_name(new_name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+        let old_nid = match self.ef.lookup_at(old_dnid, &old_name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(self.e2i(e, "rename", old_dnid));
+                return;
+            }
+        };
+        // dentries of on-disk names if different from the names given
+        let mut aliases = vec![];
+        if let Some(v) = self.get_case_alias(old_nid, &old_name) {
+            aliases.push((old_dnid, v));
+        }
+        get_node_mut!(self.ef, old_nid).put();
+        if self.unlinked.contains(&old_nid) {
+            reply.error(libc::ENOENT);
             return;
         }
+        // an open file being replaced is hidden the same way unlink does
+        let mut replaced = None;
+        let mut hidden = None;
+        if let Ok(nid) = self.ef.lookup_at(new_dnid, &new_name) {
+            let hide = nid != old_nid
+                && !self.unlinked.contains(&nid)
+                && !get_node!(self.ef, nid).is_directory()
+                && self.is_open(nid);
+            // same node if only case differs, which libexfat renames in place
+            if nid != old_nid {
+                if let Some(v) = self.get_case_alias(nid, &new_name) {
+                    aliases.push((new_dnid, v));
+                }
+            }
+            get_node_mut!(self.ef, nid).put();
+            if nid != old_nid && (flags & RENAME_NOREPLACE) != 0 {
+                reply.error(libc::EEXIST);
+                return;
+            }
+            if hide {
+                if let Err(e) = self.hide_node(new_dnid, &new_name, nid) {
+                    reply.error(self.e2i(e, "rename", old_dnid));
+                    return;
+                }
+                hidden = Some(nid);
+            } else if nid != old_nid {
+                replaced = Some(nid);
+            }
+        }
+        if let Err(e) = self.ef.rename_at(old_dnid, &old_name, new_dnid, &new_name) {
+            // otherwise the target is unlinked on its last release
+            if let Some(nid) = hidden {
+                if let Err(e) = self.unhide_node(new_dnid, &new_name, nid) {
+                    log::error!("failed to restore nid {nid}: {e}");
+                }
+            }
+            reply.error(self.e2i(e, "rename", old_dnid));
+            return;
+        }
+        self.invalidate_cursors(old_dnid);
+        self.invalidate_cursors(new_dnid);
+        self.inval_negatives(new_dnid, &new_name);
+        // kernel may still have attributes of the overwritten target cached
+        if let Some(nid) = replaced {
+            self.cache_attrs.remove(&nid);
+            self.inval_inode(nid);
+        }
+        for (dnid, name) in &aliases {
+            self.inval_entry(*dnid, name);
+        }
         reply.ok();
     }
 
@@ -441,11 +1337,20 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("nid {nid} flags {flags:#x}");
         let _mtx = mtx_lock!(MTX);
+        if self.ro && ((flags & libc::O_ACCMODE) != libc::O_RDONLY || (flags & libc::O_TRUNC) != 0)
+        {
+            reply.error(libc::EROFS);
+            return;
+        }
         let Some(node) = self.ef.get_node(nid) else {
             reply.error(libc::ENOENT);
             return;
         };
-        assert_eq!(node.get_nid(), nid);
+        if node.get_nid() != nid {
+            log::error!("nid {nid} has nid {}", node.get_nid());
+            reply.error(libc::EIO);
+            return;
+        }
         get_node_mut!(self.ef, nid).get(); // put on release
 
         // https://docs.rs/fuser/latest/fuser/trait.Filesystem.html#method.open
@@ -453,12 +1358,14 @@ impl fuser::Filesystem for crate::ExfatFuse {
         // are available in flags.".
         if (flags & libc::O_TRUNC) != 0 {
             if let Err(e) = self.ef.truncate(nid, 0, true) {
-                reply.error(e2i(e));
+                get_node_mut!(self.ef, nid).put();
+                reply.error(self.e2i(e, "open", nid));
                 return;
             }
+            self.inval_inode(nid);
         }
-        self.total_open += 1;
-        reply.opened(nid, fuser::consts::FOPEN_KEEP_CACHE);
+        let fh = self.alloc_handle(nid, flags, req.pid());
+        reply.opened(fh, self.get_open_flags(nid, fh));
     }
 
     fn read(
@@ -478,16 +1385,31 @@ impl fuser::Filesystem for crate::ExfatFuse {
             lock_owner {lock_owner:?}"
         );
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
+        match self.get_handle(nid, fh) {
+            Some(h) if h.is_readable() => (),
+            _ => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        }
+        let Ok(offset) = u64::try_from(offset) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
         let mut buf = vec![0; size.try_into().unwrap()];
-        let bytes = match self.ef.pread(nid, &mut buf, offset.try_into().unwrap()) {
+        let bytes = match self.ef.pread(nid, &mut buf, offset) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "read", nid));
                 return;
             }
         };
-        reply.data(&buf[..bytes.try_into().unwrap()]);
+        let Some(buf) = usize::try_from(bytes).ok().and_then(|n| buf.get(..n)) else {
+            log::error!("nid {nid} read {bytes} bytes into {size} bytes buffer");
+            reply.error(libc::EIO);
+            return;
+        };
+        reply.data(buf);
     }
 
     fn write(
@@ -509,15 +1431,63 @@ impl fuser::Filesystem for crate::ExfatFuse {
             data.len()
         );
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
-        let bytes = match self.ef.pwrite(nid, data, offset.try_into().unwrap()) {
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let (append, sync) = match self.get_handle(nid, fh) {
+            Some(h) if h.is_writable() => (h.is_append(), h.is_sync()),
+            _ => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
+        // offset from kernel may be stale for O_APPEND
+        let pos = if append {
+            match self.ef.stat(nid) {
+                Ok(v) => v.st_size,
+                Err(e) => {
+                    reply.error(self.e2i(e, "write", nid));
+                    return;
+                }
+            }
+        } else if let Ok(v) = u64::try_from(offset) {
+            v
+        } else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        let bytes = match self.ef.pwrite(nid, data, pos) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "write", nid));
+                return;
+            }
+        };
+        // kernel's size and page cache don't reflect where data went
+        if u64::try_from(offset) != Ok(pos) {
+            self.inval_inode(nid);
+        }
+        // O_SYNC and O_DSYNC write is durable on return
+        if sync {
+            if let Err(e) = self.ef.flush_node(nid) {
+                reply.error(self.e2i(e, "write", nid));
+                return;
+            }
+            if let Err(e) = self.ef.flush() {
+                reply.error(self.e2i(e, "write", nid));
+                return;
+            }
+            if let Err(e) = self.ef.fsync() {
+                reply.error(self.e2i(e, "write", nid));
                 return;
             }
+        }
+        let Ok(bytes) = u32::try_from(bytes) else {
+            reply.error(libc::EOVERFLOW);
+            return;
         };
-        reply.written(bytes.try_into().unwrap());
+        reply.written(bytes);
     }
 
     fn flush(
@@ -531,9 +1501,19 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("nid {nid} fh {fh} lock_owner {lock_owner:?}");
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        // POSIX locks are released on close(2) of any fd
+        self.locks.release(nid, lock_owner);
+        // nothing is written back after corruption
+        if self.ro {
+            reply.ok();
+            return;
+        }
         if let Err(e) = self.ef.flush_node(nid) {
-            reply.error(e2i(e));
+            reply.error(self.e2i(e, "flush", nid));
             return;
         }
         reply.ok();
@@ -555,15 +1535,32 @@ impl fuser::Filesystem for crate::ExfatFuse {
             lock_owner {lock_owner:?}"
         );
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
-        if let Err(e) = self.ef.flush_node(nid) {
-            reply.error(e2i(e));
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
             return;
         }
-        assert!(self.total_open > 0);
-        self.total_open -= 1;
-        get_node_mut!(self.ef, nid).put();
-        reply.ok();
+        // the handle is freed regardless, as kernel ignores this error
+        let result = if self.ro {
+            Ok(())
+        } else {
+            self.ef.flush_node(nid)
+        };
+        let h = self.free_handle(nid, fh).unwrap();
+        log::debug!("fh {fh} pid {} flags {:#x}", h.get_pid(), h.get_flags());
+        if !self.is_open(nid) {
+            self.locks.release_all(nid);
+        }
+        // kernel has seen changes via this mount, unless they bypassed page cache
+        if self.opt.cache == crate::CachePolicy::Auto && !h.is_direct() {
+            self.update_cache_attr(nid);
+        }
+        if let Err(e) = self.put_opened_node(nid) {
+            log::error!("failed to unlink nid {nid}: {e}");
+        }
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.e2i(e, "release", nid)),
+        }
     }
 
     fn fsync(
@@ -577,18 +1574,25 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("nid {nid} fh {fh} datasync {datasync}");
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
         if let Err(e) = self.ef.flush_nodes() {
-            reply.error(e2i(e));
+            reply.error(self.e2i(e, "fsync", nid));
             return;
         }
         if let Err(e) = self.ef.flush() {
-            reply.error(e2i(e));
+            reply.error(self.e2i(e, "fsync", nid));
             return;
         }
         // libexfat's fsync is to fsync device fd, not to fsync this nid...
         if let Err(e) = self.ef.fsync() {
-            reply.error(e2i(e));
+            reply.error(self.e2i(e, "fsync", nid));
             return;
         }
         reply.ok();
@@ -602,10 +1606,26 @@ impl fuser::Filesystem for crate::ExfatFuse {
             reply.error(libc::ENOENT);
             return;
         };
-        assert_eq!(node.get_nid(), nid);
+        if node.get_nid() != nid {
+            log::error!("nid {nid} has nid {}", node.get_nid());
+            reply.error(libc::EIO);
+            return;
+        }
+        if !node.is_directory() {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+        let dir = match self.opendir_cursor(nid) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(self.e2i(e, "opendir", nid));
+                return;
+            }
+        };
         get_node_mut!(self.ef, nid).get(); // put on releasedir
-        self.total_open += 1;
-        reply.opened(nid, fuser::consts::FOPEN_KEEP_CACHE);
+        let fh = self.alloc_handle(nid, flags, req.pid());
+        self.handles.get_mut(&fh).unwrap().set_dir(dir);
+        reply.opened(fh, self.get_open_flags(nid, fh));
     }
 
     fn readdir(
@@ -619,80 +1639,33 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("dnid {dnid} fh {fh} offset {offset}");
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(dnid, fh);
-        let Some(dnode) = self.ef.get_node(dnid) else {
-            reply.error(libc::ENOENT);
+        if let Err(e) = self.fill_dir(dnid, fh, offset, |ino, off, attr, name| {
+            reply.add(ino, off, attr.kind, name)
+        }) {
+            reply.error(e);
             return;
-        };
-        if !dnode.is_directory() {
-            reply.error(libc::ENOTDIR);
-            return;
-        }
-
-        let mut offset = offset;
-        if offset < 1 {
-            if reply.add(dnode.get_nid(), 1, fuser::FileType::Directory, ".") {
-                reply.ok();
-                return;
-            }
-            offset += 1;
-        }
-        if offset < 2 {
-            if reply.add(dnode.get_pnid(), 2, fuser::FileType::Directory, "..") {
-                reply.ok();
-                return;
-            }
-            offset += 1;
         }
+        reply.ok();
+    }
 
-        let mut c = match self.ef.opendir_cursor(dnid) {
-            Ok(v) => v,
-            Err(e) => {
-                reply.error(e2i(e));
-                return;
-            }
-        };
-        let mut next = 3;
-        loop {
-            let nid = match self.ef.readdir_cursor(&mut c) {
-                Ok(v) => v,
-                Err(e) => {
-                    if let libexfat::Error::Errno(e) = e {
-                        if e == nix::errno::Errno::ENOENT {
-                            break;
-                        }
-                    }
-                    self.ef.closedir_cursor(c);
-                    reply.error(e2i(e));
-                    return;
-                }
-            };
-            if offset < next {
-                let node = get_node!(self.ef, nid);
-                let st = match self.ef.stat(nid) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        get_node_mut!(self.ef, nid).put();
-                        self.ef.closedir_cursor(c);
-                        reply.error(e2i(e));
-                        return;
-                    }
-                };
-                if reply.add(
-                    st.st_ino,
-                    next,
-                    crate::util::mode2kind(st.st_mode),
-                    node.get_name(),
-                ) {
-                    get_node_mut!(self.ef, nid).put();
-                    break;
-                }
-                offset += 1;
-            }
-            get_node_mut!(self.ef, nid).put();
-            next += 1;
+    fn readdirplus(
+        &mut self,
+        req: &fuser::Request<'_>,
+        dnid: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("dnid {dnid} fh {fh} offset {offset}");
+        let _mtx = mtx_lock!(MTX);
+        let ttl = self.opt.entry_timeout;
+        if let Err(e) = self.fill_dir(dnid, fh, offset, |ino, off, attr, name| {
+            reply.add(ino, off, name, &ttl, attr, 0)
+        }) {
+            reply.error(e);
+            return;
         }
-        self.ef.closedir_cursor(c);
         reply.ok();
     }
 
@@ -707,9 +1680,17 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("nid {nid} fh {fh} flags {flags:#x}");
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
-        assert!(self.total_open > 0);
-        self.total_open -= 1;
+        let Some(mut h) = self.free_handle(nid, fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        log::debug!("fh {fh} pid {} flags {:#x}", h.get_pid(), h.get_flags());
+        if let Some(dir) = h.take_dir() {
+            self.closedir_cursor(dir);
+        }
+        if self.opt.cache == crate::CachePolicy::Auto && !h.is_direct() {
+            self.update_cache_attr(nid);
+        }
         get_node_mut!(self.ef, nid).put();
         reply.ok();
     }
@@ -742,8 +1723,125 @@ impl fuser::Filesystem for crate::ExfatFuse {
                 v.f_namelen,
                 v.f_frsize,
             ),
-            Err(e) => reply.error(e2i(e)),
+            Err(e) => reply.error(self.e2i(e, "statfs", nid)),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        name: &std::ffi::OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("nid {nid} name {name:?} value {value:?} flags {flags:#x} position {position}");
+        let _mtx = mtx_lock!(MTX);
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let Some(node) = self.ef.get_node(nid) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if name != XATTR_ATTRIBUTES {
+            reply.error(if XATTR_LIST.iter().any(|x| name == *x) {
+                libc::EPERM // read-only
+            } else {
+                libc::ENOTSUP
+            });
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        if (flags & libc::XATTR_CREATE) != 0 {
+            reply.error(libc::EEXIST); // always exists
+            return;
+        }
+        let Some(attrib) = crate::util::parse_attrib(value) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        if !crate::util::is_valid_attrib_change(node.get_attrib(), attrib) {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if let Err(e) = self.set_attrib(nid, attrib) {
+            reply.error(self.e2i(e, "setxattr", nid));
+            return;
+        }
+        reply.ok();
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        name: &std::ffi::OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("nid {nid} name {name:?} size {size}");
+        let _mtx = mtx_lock!(MTX);
+        if self.ef.get_node(nid).is_none() {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let Some(s) = self.get_xattr(nid, name) else {
+            reply.error(ENOATTR);
+            return;
+        };
+        reply_xattr(reply, size, s.as_bytes());
+    }
+
+    fn listxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("nid {nid} size {size}");
+        let _mtx = mtx_lock!(MTX);
+        if self.ef.get_node(nid).is_none() {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        // read-only ones are left out, otherwise copying xattrs
+        // (e.g. cp -a, rsync -X) fails with EPERM on setting them
+        let mut b = XATTR_ATTRIBUTES.as_bytes().to_vec();
+        b.push(0);
+        reply_xattr(reply, size, &b);
+    }
+
+    fn removexattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("nid {nid} name {name:?}");
+        let _mtx = mtx_lock!(MTX);
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if self.ef.get_node(nid).is_none() {
+            reply.error(libc::ENOENT);
+            return;
         }
+        reply.error(if XATTR_LIST.iter().any(|x| name == *x) {
+            libc::EPERM // can't be removed
+        } else {
+            ENOATTR
+        });
     }
 
     // https://docs.rs/fuser/latest/fuser/trait.Filesystem.html
@@ -752,8 +1850,15 @@ impl fuser::Filesystem for crate::ExfatFuse {
         debug_req!(req, self.debug > 1);
         log::debug!("nid {nid} mask {mask:#o}");
         let _mtx = mtx_lock!(MTX);
-        reply.ok();
-        panic!("access");
+        if self.ef.get_node(nid).is_none() {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        if (mask & libc::W_OK) != 0 && (self.ro || self.ef.is_readonly()) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        reply.ok(); // permission bits are checked by kernel
     }
 
     fn create(
@@ -772,27 +1877,134 @@ impl fuser::Filesystem for crate::ExfatFuse {
             flags {flags:#x}"
         );
         let _mtx = mtx_lock!(MTX);
-        let Some(name) = name.to_str() else {
-            reply.error(libc::EINVAL);
+        if self.ro {
+            reply.error(libc::EROFS);
             return;
+        }
+        let name = match self.decode_name(name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
         };
-        let nid = match self.ef.mknod_at(dnid, name) {
+        if let Err(e) = self.check_exist(dnid, &name, "create") {
+            reply.error(e);
+            return;
+        }
+        let nid = match self.ef.mknod_at(dnid, &name) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "create", dnid));
                 return;
             }
         };
+        self.invalidate_cursors(dnid);
+        self.inval_negatives(dnid, &name);
         get_node_mut!(self.ef, nid).get(); // put on release
         let st = match self.ef.stat(nid) {
             Ok(v) => v,
             Err(e) => {
-                reply.error(e2i(e));
+                reply.error(self.e2i(e, "create", dnid));
                 return;
             }
         };
-        self.total_open += 1;
-        reply.created(&TTL, &stat2attr(&st), 0, nid, 0);
+        let attr = match self.stat2attr(&st) {
+            Ok(v) => v,
+            Err(e) => {
+                get_node_mut!(self.ef, nid).put();
+                reply.error(e);
+                return;
+            }
+        };
+        let fh = self.alloc_handle(nid, flags, req.pid());
+        let open_flags = self.get_open_flags(nid, fh);
+        reply.created(&self.opt.entry_timeout, &attr, 0, fh, open_flags);
+    }
+
+    fn getlk(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!(
+            "nid {nid} fh {fh} lock_owner {lock_owner} start {start} end {end} \
+            typ {typ} pid {pid}"
+        );
+        let _mtx = mtx_lock!(MTX);
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        let lock = crate::lock::Lock {
+            start,
+            end,
+            typ,
+            pid,
+            owner: lock_owner,
+        };
+        match self.locks.get_conflict(nid, &lock) {
+            Some(l) => reply.locked(l.start, l.end, l.typ, l.pid),
+            None => reply.locked(start, end, libc::F_UNLCK.into(), 0),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!(
+            "nid {nid} fh {fh} lock_owner {lock_owner} start {start} end {end} \
+            typ {typ} pid {pid} sleep {sleep}"
+        );
+        let _mtx = mtx_lock!(MTX);
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        if typ != i32::from(libc::F_RDLCK)
+            && typ != i32::from(libc::F_WRLCK)
+            && typ != i32::from(libc::F_UNLCK)
+        {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if start > end {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let lock = crate::lock::Lock {
+            start,
+            end,
+            typ,
+            pid,
+            owner: lock_owner,
+        };
+        if self.locks.set(nid, lock) {
+            reply.ok();
+        } else if sleep {
+            self.locks.wait(nid, lock, reply); // F_SETLKW
+        } else {
+            reply.error(libc::EAGAIN);
+        }
     }
 
     // Not supported on FreeBSD (see fuse_vnop_ioctl()).
@@ -813,23 +2025,38 @@ impl fuser::Filesystem for crate::ExfatFuse {
             out_size {out_size}"
         );
         let _mtx = mtx_lock!(MTX);
-        assert_eq!(nid, fh);
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
         if u64::from(cmd) == libexfat::ctl::CTL_NIDPRUNE_ENCODE {
             log::debug!("CTL_NIDPRUNE");
-            assert!(self.total_open > 0); // fd for this nid
-            let x = self.total_open - 1;
+            assert!(!self.handles.is_empty()); // fd for this nid
+            let x = self.handles.len() - 1;
             if x > 0 {
                 log::error!("{x} pending open file");
                 reply.error(libc::EBUSY);
                 return;
             }
+            self.invalidate_cursors(nid);
+            // nids of pruned children are no longer valid in kernel
+            let names = match self.get_child_names(nid) {
+                Ok(v) => v,
+                Err(e) => {
+                    reply.error(self.e2i(e, "ioctl", nid));
+                    return;
+                }
+            };
             let t = match self.ef.prune_node(nid) {
                 Ok(v) => v,
                 Err(e) => {
-                    reply.error(e2i(e));
+                    reply.error(self.e2i(e, "ioctl", nid));
                     return;
                 }
             };
+            for name in &names {
+                self.inval_entry(nid, name);
+            }
             let mut b = [0; 16];
             byteorder::BigEndian::write_u64_into(&[t.0.try_into().unwrap()], &mut b[..8]);
             byteorder::BigEndian::write_u64_into(&[t.1.try_into().unwrap()], &mut b[8..]);
@@ -839,4 +2066,213 @@ impl fuser::Filesystem for crate::ExfatFuse {
             reply.error(libc::EINVAL);
         }
     }
+
+    fn fallocate(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("nid {nid} fh {fh} offset {offset} length {length} mode {mode:#x}");
+        let _mtx = mtx_lock!(MTX);
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
+        match self.get_handle(nid, fh) {
+            Some(h) if h.is_writable() => (),
+            _ => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        }
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        if (mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE)) != 0 {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+        let Some(end) = offset.checked_add(length) else {
+            reply.error(libc::EFBIG);
+            return;
+        };
+        let (Ok(offset), Ok(end)) = (u64::try_from(offset), u64::try_from(end)) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        if (mode & FALLOC_FL_PUNCH_HOLE) != 0 {
+            if (mode & FALLOC_FL_KEEP_SIZE) == 0 {
+                reply.error(libc::EINVAL);
+                return;
+            }
+            if let Err(e) = self.punch_hole(nid, offset, end) {
+                reply.error(self.e2i(e, "fallocate", nid));
+                return;
+            }
+            reply.ok();
+            return;
+        }
+        let st = match self.ef.stat(nid) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(self.e2i(e, "fallocate", nid));
+                return;
+            }
+        };
+        // clusters are allocated up to the file size
+        if end <= st.st_size {
+            reply.ok();
+            return;
+        }
+        // libexfat can't allocate clusters beyond the file size
+        if (mode & FALLOC_FL_KEEP_SIZE) != 0 {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+        // Extend without erasing new clusters. ValidDataLength is left as is,
+        // so reading beyond it returns zeros without device I/O.
+        // libexfat allocates contiguous clusters (NoFatChain) if possible.
+        if let Err(e) = self.ef.truncate(nid, end, false) {
+            if self.ef.flush_node(nid).is_err() {
+                // ignore this error
+            }
+            reply.error(self.e2i(e, "fallocate", nid));
+            return;
+        }
+        if let Err(e) = self.ef.flush_node(nid) {
+            reply.error(self.e2i(e, "fallocate", nid));
+            return;
+        }
+        reply.ok();
+    }
+
+    fn lseek(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!("nid {nid} fh {fh} offset {offset} whence {whence}");
+        let _mtx = mtx_lock!(MTX);
+        if self.get_handle(nid, fh).is_none() {
+            reply.error(libc::EBADF);
+            return;
+        }
+        // kernel handles others by itself
+        if whence != libc::SEEK_DATA && whence != libc::SEEK_HOLE {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let Ok(offset) = u64::try_from(offset) else {
+            reply.error(libc::ENXIO);
+            return;
+        };
+        let st = match self.ef.stat(nid) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(self.e2i(e, "lseek", nid));
+                return;
+            }
+        };
+        if offset >= st.st_size {
+            reply.error(libc::ENXIO);
+            return;
+        }
+        // data is followed by a hole beyond ValidDataLength till EOF
+        let valid_size = get_node!(self.ef, nid).get_valid_size().min(st.st_size);
+        let offset = if whence == libc::SEEK_DATA {
+            if offset >= valid_size {
+                reply.error(libc::ENXIO);
+                return;
+            }
+            offset
+        } else {
+            offset.max(valid_size)
+        };
+        let Ok(offset) = i64::try_from(offset) else {
+            reply.error(libc::EOVERFLOW);
+            return;
+        };
+        reply.offset(offset);
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &fuser::Request<'_>,
+        nid_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        nid_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        debug_req!(req, self.debug > 1);
+        log::debug!(
+            "nid_in {nid_in} fh_in {fh_in} offset_in {offset_in} \
+            nid_out {nid_out} fh_out {fh_out} offset_out {offset_out} \
+            len {len} flags {flags:#x}"
+        );
+        let _mtx = mtx_lock!(MTX);
+        if self.ro {
+            reply.error(libc::EROFS);
+            return;
+        }
+        match self.get_handle(nid_in, fh_in) {
+            Some(h) if h.is_readable() => (),
+            _ => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        }
+        match self.get_handle(nid_out, fh_out) {
+            Some(h) if h.is_writable() => (),
+            _ => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        }
+        if flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let (Ok(offset_in), Ok(offset_out)) = (u64::try_from(offset_in), u64::try_from(offset_out))
+        else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        // written size is replied in u32
+        let len = len.min(u32::MAX.into());
+        if offset_out.checked_add(len).is_none() {
+            reply.error(libc::EFBIG);
+            return;
+        }
+        let result = self.copy_range(nid_in, offset_in, nid_out, offset_out, len);
+        if let Err(e) = self.ef.flush_node(nid_out) {
+            if result.is_ok() {
+                reply.error(self.e2i(e, "copy_file_range", nid_out));
+                return;
+            }
+        }
+        match result {
+            Ok(v) => match u32::try_from(v) {
+                Ok(v) => reply.written(v),
+                Err(_) => reply.error(libc::EOVERFLOW),
+            },
+            Err(e) => reply.error(self.e2i(e, "copy_file_range", nid_out)),
+        }
+    }
 }
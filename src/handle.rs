@@ -0,0 +1,72 @@
+// readdir state kept across readdir calls on the same directory handle
+pub(crate) struct Dir {
+    pub(crate) cursor: Option<libexfat::exfat::Cursor>, // None if dir modified
+    pub(crate) next: i64,                               // offset of next entry
+    pub(crate) pending: Option<u64>,                    // nid read but not yet replied, not put
+    pub(crate) seen: std::collections::HashSet<u64>,    // nids already replied
+}
+
+pub(crate) struct Handle {
+    nid: u64,
+    flags: i32,
+    pid: u32,
+    dir: Option<Dir>,
+}
+
+impl Handle {
+    pub(crate) fn new(nid: u64, flags: i32, pid: u32) -> Self {
+        Self {
+            nid,
+            flags,
+            pid,
+            dir: None,
+        }
+    }
+
+    pub(crate) fn get_nid(&self) -> u64 {
+        self.nid
+    }
+
+    pub(crate) fn get_flags(&self) -> i32 {
+        self.flags
+    }
+
+    pub(crate) fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub(crate) fn is_readable(&self) -> bool {
+        let accmode = self.flags & libc::O_ACCMODE;
+        accmode == libc::O_RDONLY || accmode == libc::O_RDWR
+    }
+
+    pub(crate) fn is_writable(&self) -> bool {
+        let accmode = self.flags & libc::O_ACCMODE;
+        accmode == libc::O_WRONLY || accmode == libc::O_RDWR
+    }
+
+    pub(crate) fn is_append(&self) -> bool {
+        (self.flags & libc::O_APPEND) != 0
+    }
+
+    pub(crate) fn is_direct(&self) -> bool {
+        (self.flags & libc::O_DIRECT) != 0
+    }
+
+    pub(crate) fn is_sync(&self) -> bool {
+        (self.flags & (libc::O_SYNC | libc::O_DSYNC)) != 0
+    }
+
+    pub(crate) fn get_dir_mut(&mut self) -> Option<&mut Dir> {
+        self.dir.as_mut()
+    }
+
+    pub(crate) fn take_dir(&mut self) -> Option<Dir> {
+        self.dir.take()
+    }
+
+    pub(crate) fn set_dir(&mut self, dir: Dir) {
+        assert!(self.dir.is_none());
+        self.dir = Some(dir);
+    }
+}
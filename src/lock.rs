@@ -0,0 +1,334 @@
+// POSIX advisory locks kept in the daemon, so that they respect lock_owner.
+// BSD flock(2) locks are left to kernel, see init.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Lock {
+    pub(crate) start: u64,
+    pub(crate) end: u64, // inclusive
+    pub(crate) typ: i32,
+    pub(crate) pid: u32,
+    pub(crate) owner: u64,
+}
+
+impl Lock {
+    fn is_overlapped(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn is_conflicting(&self, lock: &Lock) -> bool {
+        self.owner != lock.owner
+            && self.is_overlapped(lock.start, lock.end)
+            && (self.typ == i32::from(libc::F_WRLCK) || lock.typ == i32::from(libc::F_WRLCK))
+    }
+}
+
+// reply of a deferred F_SETLKW request, replaced in tests
+pub(crate) trait WaiterReply {
+    fn ok(self);
+    fn error(self, err: i32);
+}
+
+impl WaiterReply for fuser::ReplyEmpty {
+    fn ok(self) {
+        fuser::ReplyEmpty::ok(self);
+    }
+
+    fn error(self, err: i32) {
+        fuser::ReplyEmpty::error(self, err);
+    }
+}
+
+// F_SETLKW request waiting for a conflicting lock to be released
+struct Waiter<R> {
+    nid: u64,
+    lock: Lock,
+    reply: R,
+}
+
+pub(crate) struct LockManager<R = fuser::ReplyEmpty> {
+    locks: std::collections::HashMap<u64, Vec<Lock>>,
+    waiters: Vec<Waiter<R>>,
+}
+
+impl<R: WaiterReply> LockManager<R> {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: std::collections::HashMap::new(),
+            waiters: vec![],
+        }
+    }
+
+    pub(crate) fn get_conflict(&self, nid: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&nid)?
+            .iter()
+            .find(|l| l.is_conflicting(lock))
+            .copied()
+    }
+
+    // Returns false if lock conflicts with a lock of other owner.
+    pub(crate) fn set(&mut self, nid: u64, lock: Lock) -> bool {
+        if lock.typ != i32::from(libc::F_UNLCK) && self.get_conflict(nid, &lock).is_some() {
+            return false;
+        }
+        self.insert(nid, lock);
+        // unlocked or downgraded range may unblock waiters
+        self.wake();
+        true
+    }
+
+    fn insert(&mut self, nid: u64, lock: Lock) {
+        let v = self.locks.entry(nid).or_default();
+        // existing range of this owner is replaced by the new one
+        let mut l = vec![];
+        for x in v.drain(..) {
+            if x.owner != lock.owner || !x.is_overlapped(lock.start, lock.end) {
+                l.push(x);
+                continue;
+            }
+            if x.start < lock.start {
+                l.push(Lock {
+                    end: lock.start - 1,
+                    ..x
+                });
+            }
+            if x.end > lock.end {
+                l.push(Lock {
+                    start: lock.end + 1,
+                    ..x
+                });
+            }
+        }
+        if lock.typ != i32::from(libc::F_UNLCK) {
+            l.push(lock);
+        }
+        if l.is_empty() {
+            self.locks.remove(&nid);
+        } else {
+            *v = l;
+        }
+    }
+
+    // Reply is deferred until the lock is acquired.
+    pub(crate) fn wait(&mut self, nid: u64, lock: Lock, reply: R) {
+        if self.is_deadlock(nid, &lock) {
+            log::debug!("nid {nid} {lock:?} deadlock");
+            reply.error(libc::EDEADLK);
+            return;
+        }
+        log::debug!("nid {nid} {lock:?} waiting");
+        self.waiters.push(Waiter { nid, lock, reply });
+    }
+
+    fn get_blockers(&self, nid: u64, lock: &Lock) -> Vec<u64> {
+        self.locks.get(&nid).map_or(vec![], |v| {
+            v.iter()
+                .filter(|l| l.is_conflicting(lock))
+                .map(|l| l.owner)
+                .collect()
+        })
+    }
+
+    // Returns true if an owner holding a conflicting lock is waiting for
+    // the owner of lock, either directly or via other waiters.
+    fn is_deadlock(&self, nid: u64, lock: &Lock) -> bool {
+        let mut stack = self.get_blockers(nid, lock);
+        let mut seen = std::collections::HashSet::new();
+        while let Some(owner) = stack.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+            if !seen.insert(owner) {
+                continue;
+            }
+            for w in self.waiters.iter().filter(|w| w.lock.owner == owner) {
+                stack.extend(self.get_blockers(w.nid, &w.lock));
+            }
+        }
+        false
+    }
+
+    // Waiters are dropped when their owner releases locks, e.g. another
+    // thread closes the fd. This is the only way out of F_SETLKW other than
+    // acquiring the lock, as fuser replies ENOSYS to FUSE_INTERRUPT, so
+    // kernel keeps an interrupted or killed process waiting.
+    fn cancel<F>(&mut self, f: F)
+    where
+        F: Fn(&Waiter<R>) -> bool,
+    {
+        let mut i = 0;
+        while i < self.waiters.len() {
+            if f(&self.waiters[i]) {
+                let w = self.waiters.remove(i);
+                log::debug!("nid {} {:?} cancelled", w.nid, w.lock);
+                w.reply.error(libc::EINTR);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // Releases all locks of owner on nid, e.g. on flush (close(2)).
+    pub(crate) fn release(&mut self, nid: u64, owner: u64) {
+        self.cancel(|w| w.nid == nid && w.lock.owner == owner);
+        self.set(
+            nid,
+            Lock {
+                start: 0,
+                end: u64::MAX,
+                typ: i32::from(libc::F_UNLCK),
+                pid: 0,
+                owner,
+            },
+        );
+    }
+
+    // Releases all locks on nid, e.g. on last release of nid.
+    pub(crate) fn release_all(&mut self, nid: u64) {
+        self.cancel(|w| w.nid == nid);
+        if self.locks.remove(&nid).is_some() {
+            self.wake();
+        }
+    }
+
+    fn wake(&mut self) {
+        let mut i = 0;
+        while i < self.waiters.len() {
+            let (nid, lock) = (self.waiters[i].nid, self.waiters[i].lock);
+            if self.get_conflict(nid, &lock).is_some() {
+                i += 1;
+                continue;
+            }
+            let w = self.waiters.remove(i);
+            log::debug!("nid {nid} {lock:?} acquired");
+            self.insert(nid, lock);
+            w.reply.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    type Replied = std::rc::Rc<std::cell::Cell<Option<i32>>>;
+
+    // 0 if replied ok, errno otherwise
+    struct TestReply(Replied);
+
+    impl super::WaiterReply for TestReply {
+        fn ok(self) {
+            self.0.set(Some(0));
+        }
+
+        fn error(self, err: i32) {
+            self.0.set(Some(err));
+        }
+    }
+
+    fn lock(start: u64, end: u64, typ: libc::c_int, owner: u64) -> super::Lock {
+        super::Lock {
+            start,
+            end,
+            typ: i32::from(typ),
+            pid: 0,
+            owner,
+        }
+    }
+
+    fn get_ranges(lm: &super::LockManager<TestReply>, nid: u64) -> Vec<(u64, u64, i32)> {
+        let mut v = lm.locks.get(&nid).map_or(vec![], |v| {
+            v.iter().map(|l| (l.start, l.end, l.typ)).collect()
+        });
+        v.sort_unstable();
+        v
+    }
+
+    fn wait(lm: &mut super::LockManager<TestReply>, nid: u64, lock: super::Lock) -> Replied {
+        let result = Replied::default();
+        lm.wait(nid, lock, TestReply(result.clone()));
+        result
+    }
+
+    #[test]
+    fn test_insert_split() {
+        let mut lm = super::LockManager::<TestReply>::new();
+        let (rd, wr) = (i32::from(libc::F_RDLCK), i32::from(libc::F_WRLCK));
+        assert!(lm.set(1, lock(0, 99, libc::F_RDLCK, 1)));
+        assert!(lm.set(1, lock(10, 19, libc::F_WRLCK, 1)));
+        assert_eq!(get_ranges(&lm, 1), [(0, 9, rd), (10, 19, wr), (20, 99, rd)]);
+        assert!(lm.set(1, lock(5, 29, libc::F_UNLCK, 1)));
+        assert_eq!(get_ranges(&lm, 1), [(0, 4, rd), (30, 99, rd)]);
+        assert!(lm.set(1, lock(0, u64::MAX, libc::F_WRLCK, 1)));
+        assert_eq!(get_ranges(&lm, 1), [(0, u64::MAX, wr)]);
+        assert!(lm.set(1, lock(0, u64::MAX, libc::F_UNLCK, 1)));
+        assert!(!lm.locks.contains_key(&1));
+    }
+
+    #[test]
+    fn test_conflict() {
+        let mut lm = super::LockManager::<TestReply>::new();
+        assert!(lm.set(1, lock(0, 9, libc::F_RDLCK, 1)));
+        assert!(lm.set(1, lock(5, 14, libc::F_RDLCK, 2)));
+        assert!(!lm.set(1, lock(9, 9, libc::F_WRLCK, 2)));
+        assert!(lm.set(1, lock(10, 19, libc::F_WRLCK, 2)));
+        assert!(lm.set(2, lock(0, 9, libc::F_WRLCK, 2)));
+        assert_eq!(
+            lm.get_conflict(1, &lock(12, 12, libc::F_RDLCK, 1))
+                .unwrap()
+                .owner,
+            2
+        );
+        assert!(lm
+            .get_conflict(1, &lock(20, 29, libc::F_WRLCK, 1))
+            .is_none());
+    }
+
+    #[test]
+    fn test_wake() {
+        let mut lm = super::LockManager::<TestReply>::new();
+        assert!(lm.set(1, lock(0, 9, libc::F_WRLCK, 1)));
+        let result = wait(&mut lm, 1, lock(5, 5, libc::F_WRLCK, 2));
+        assert_eq!(result.get(), None);
+        assert!(lm.set(1, lock(0, 4, libc::F_UNLCK, 1)));
+        assert_eq!(result.get(), None);
+        assert!(lm.set(1, lock(5, 9, libc::F_RDLCK, 1)));
+        assert_eq!(result.get(), None);
+        lm.release(1, 1);
+        assert_eq!(result.get(), Some(0));
+        assert!(lm.get_conflict(1, &lock(5, 5, libc::F_RDLCK, 1)).is_some());
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mut lm = super::LockManager::<TestReply>::new();
+        assert!(lm.set(1, lock(0, 0, libc::F_WRLCK, 1)));
+        assert!(lm.set(1, lock(1, 1, libc::F_WRLCK, 2)));
+        assert!(lm.set(2, lock(0, 0, libc::F_WRLCK, 3)));
+        // 1 waits for 2, 2 waits for 3
+        let r1 = wait(&mut lm, 1, lock(1, 1, libc::F_WRLCK, 1));
+        let r2 = wait(&mut lm, 2, lock(0, 0, libc::F_WRLCK, 2));
+        assert_eq!(r1.get(), None);
+        assert_eq!(r2.get(), None);
+        // 3 waiting for 1 closes the cycle
+        let r3 = wait(&mut lm, 1, lock(0, 0, libc::F_WRLCK, 3));
+        assert_eq!(r3.get(), Some(libc::EDEADLK));
+        // 3 unlocking lets 2 then 1 go
+        lm.release(2, 3);
+        assert_eq!(r2.get(), Some(0));
+        assert_eq!(r1.get(), None);
+        lm.release(1, 2);
+        assert_eq!(r1.get(), Some(0));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut lm = super::LockManager::<TestReply>::new();
+        assert!(lm.set(1, lock(0, 9, libc::F_WRLCK, 1)));
+        let r2 = wait(&mut lm, 1, lock(0, 0, libc::F_RDLCK, 2));
+        let r3 = wait(&mut lm, 1, lock(0, 0, libc::F_RDLCK, 3));
+        lm.release(1, 2);
+        assert_eq!(r2.get(), Some(libc::EINTR));
+        assert_eq!(r3.get(), None);
+        lm.release_all(1);
+        assert_eq!(r3.get(), Some(libc::EINTR));
+        assert!(!lm.locks.contains_key(&1));
+    }
+}
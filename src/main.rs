@@ -1,4 +1,8 @@
+mod error;
 mod fuse;
+mod handle;
+mod lock;
+mod name;
 mod util;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -11,20 +15,123 @@ fn print_version() {
 const EXFAT_HOME: &str = "EXFAT_HOME";
 const EXFAT_NIDALLOC: &str = "EXFAT_NIDALLOC";
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CachePolicy {
+    Always, // always keep page cache on open
+    // Keep page cache unless mtime or size changed since last close, by
+    // writes which bypass page cache (O_DIRECT, copy_file_range, fallocate).
+    // libexfat never rereads directory entries, so changes made outside this
+    // mount (e.g. by writing to the device) aren't detected.
+    Auto,
+    Never, // bypass page cache
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorPolicy {
+    Continue,  // keep going
+    RemountRo, // fail mutating requests with EROFS
+    Panic,     // detach and exit with the volume left dirty
+}
+
+#[derive(Debug)]
+struct Opt {
+    cache: CachePolicy,
+    errors: ErrorPolicy, // on metadata corruption
+    iocharset: name::Charset,
+    badchars: name::BadChars,
+    attr_timeout: std::time::Duration,
+    entry_timeout: std::time::Duration,
+    negative_timeout: std::time::Duration,
+}
+
+impl Default for Opt {
+    fn default() -> Self {
+        Self {
+            cache: CachePolicy::Always,
+            errors: ErrorPolicy::Continue,
+            iocharset: name::Charset::Utf8,
+            badchars: name::BadChars::Reject,
+            attr_timeout: std::time::Duration::from_secs(1),
+            entry_timeout: std::time::Duration::from_secs(1),
+            negative_timeout: std::time::Duration::ZERO,
+        }
+    }
+}
+
 struct ExfatFuse {
     ef: libexfat::exfat::Exfat,
-    total_open: usize,
+    opt: Opt,
+    handles: std::collections::HashMap<u64, handle::Handle>,
+    next_fh: u64,
+    unlinked: std::collections::HashSet<u64>, // open-unlinked nids
+    locks: lock::LockManager,
+    cache_attrs: std::collections::HashMap<u64, (std::time::SystemTime, u64)>, // mtime, size
+    // negative entries replied per dnid, name and expiry
+    negatives:
+        std::collections::HashMap<u64, std::collections::HashMap<String, std::time::Instant>>,
+    notify: std::sync::mpsc::Sender<fuse::Notify>,
+    errors: error::ErrorCount,
+    ro: bool,      // no more writes after corruption with errors=remount-ro|panic
+    mntpt: String, // for errors=panic
     debug: i32,
 }
 
 impl ExfatFuse {
-    fn new(ef: libexfat::exfat::Exfat, debug: i32) -> Self {
+    fn new(
+        ef: libexfat::exfat::Exfat,
+        opt: Opt,
+        notify: std::sync::mpsc::Sender<fuse::Notify>,
+        mntpt: &str,
+        debug: i32,
+    ) -> Self {
         Self {
             ef,
-            total_open: 0,
+            opt,
+            handles: std::collections::HashMap::new(),
+            next_fh: 1,
+            unlinked: std::collections::HashSet::new(),
+            locks: lock::LockManager::new(),
+            cache_attrs: std::collections::HashMap::new(),
+            negatives: std::collections::HashMap::new(),
+            notify,
+            errors: error::ErrorCount::default(),
+            ro: false,
+            mntpt: mntpt.to_string(),
             debug,
         }
     }
+
+    fn alloc_handle(&mut self, nid: u64, flags: i32, pid: u32) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        assert!(!self.handles.contains_key(&fh));
+        self.handles
+            .insert(fh, handle::Handle::new(nid, flags, pid));
+        fh
+    }
+
+    fn get_handle(&self, nid: u64, fh: u64) -> Option<&handle::Handle> {
+        match self.handles.get(&fh) {
+            Some(v) if v.get_nid() == nid => Some(v),
+            Some(v) => {
+                log::error!("fh {fh} belongs to nid {} not {nid}", v.get_nid());
+                None
+            }
+            None => {
+                log::error!("fh {fh} not found");
+                None
+            }
+        }
+    }
+
+    fn is_open(&self, nid: u64) -> bool {
+        self.handles.values().any(|h| h.get_nid() == nid)
+    }
+
+    fn free_handle(&mut self, nid: u64, fh: u64) -> Option<handle::Handle> {
+        self.get_handle(nid, fh)?;
+        self.handles.remove(&fh)
+    }
 }
 
 fn init_std_logger() -> std::result::Result<(), log::SetLoggerError> {
@@ -94,6 +201,43 @@ fn init_syslog_logger(prog: &str) -> Result<()> {
     )
 }
 
+fn get_sigset() -> nix::sys::signal::SigSet {
+    let mut sigset = nix::sys::signal::SigSet::empty();
+    sigset.add(nix::sys::signal::Signal::SIGTERM);
+    sigset.add(nix::sys::signal::Signal::SIGINT);
+    sigset.add(nix::sys::signal::Signal::SIGHUP);
+    sigset
+}
+
+// Unmount on signal, so that Session::run returns and ExfatFuse::destroy
+// unmounts libexfat which clears the dirty bit.
+// Unmount fails with EBUSY while the mount point is in use, in which case
+// it's detached lazily and the session ends once open files are closed.
+// Signals stay blocked, so keep waiting if that fails too.
+fn wait_signal(
+    sigset: &nix::sys::signal::SigSet,
+    mut unmounter: fuser::SessionUnmounter,
+    mntpt: &str,
+) {
+    loop {
+        match sigset.wait() {
+            Ok(v) => log::info!("{v:?}"),
+            Err(e) => {
+                log::error!("{e}");
+                continue;
+            }
+        }
+        match unmounter.unmount() {
+            Ok(()) => return,
+            Err(e) => log::error!("{e}"),
+        }
+        match util::unmount_lazy(mntpt) {
+            Ok(()) => return,
+            Err(e) => log::error!("{e}"),
+        }
+    }
+}
+
 fn usage(prog: &str, gopt: &getopts::Options) {
     print!(
         "{}",
@@ -186,7 +330,15 @@ fn main() {
     gopt.optopt(
         "o",
         "",
-        "relan/exfat compatible file system specific options.",
+        "relan/exfat compatible file system specific options. \
+        Also cache=always|auto|never for page cache policy on open \
+        (auto drops page cache changed by writes bypassing it via this mount, \
+        not changes made outside this mount), \
+        errors=continue|remount-ro|panic for behavior on metadata corruption, \
+        iocharset=utf8|iso8859-1 for file name encoding, \
+        badchars=reject|escape|replace for characters exFAT forbids, \
+        attr_timeout=, entry_timeout= and negative_timeout= in seconds \
+        (default 1, 1 and 0).",
         "<options>",
     );
     gopt.optflag("d", "", "Enable env_logger logging and do not daemonize.");
@@ -264,6 +416,7 @@ fn main() {
             mopt.extend_from_slice(&[*s, &v[i]]);
         }
     }
+    let mut opt = Opt::default();
     let options = matches.opt_str("o").unwrap_or_default();
     for x in &options.split(',').collect::<Vec<&str>>() {
         let mut found = false;
@@ -279,6 +432,61 @@ fn main() {
                 found = true; // ignore
             }
         } else if l.len() == 2 {
+            if l[0] == "cache" {
+                opt.cache = match l[1] {
+                    "always" => CachePolicy::Always,
+                    "auto" => CachePolicy::Auto,
+                    "never" => CachePolicy::Never,
+                    _ => {
+                        eprintln!("invalid cache policy: {}", l[1]);
+                        std::process::exit(1);
+                    }
+                };
+                found = true;
+            } else if l[0] == "errors" {
+                opt.errors = match l[1] {
+                    "continue" => ErrorPolicy::Continue,
+                    "remount-ro" => ErrorPolicy::RemountRo,
+                    "panic" => ErrorPolicy::Panic,
+                    _ => {
+                        eprintln!("invalid error policy: {}", l[1]);
+                        std::process::exit(1);
+                    }
+                };
+                found = true;
+            } else if l[0] == "iocharset" {
+                let Some(v) = name::parse_charset(l[1]) else {
+                    eprintln!("unsupported iocharset: {}", l[1]);
+                    std::process::exit(1);
+                };
+                opt.iocharset = v;
+                found = true;
+            } else if l[0] == "badchars" {
+                opt.badchars = match l[1] {
+                    "reject" => name::BadChars::Reject,
+                    "escape" => name::BadChars::Escape,
+                    "replace" => name::BadChars::Replace,
+                    _ => {
+                        eprintln!("invalid badchars policy: {}", l[1]);
+                        std::process::exit(1);
+                    }
+                };
+                found = true;
+            } else if l[0] == "attr_timeout"
+                || l[0] == "entry_timeout"
+                || l[0] == "negative_timeout"
+            {
+                let Some(v) = util::parse_timeout(l[1]) else {
+                    eprintln!("invalid {}: {}", l[0], l[1]);
+                    std::process::exit(1);
+                };
+                match l[0] {
+                    "attr_timeout" => opt.attr_timeout = v,
+                    "entry_timeout" => opt.entry_timeout = v,
+                    _ => opt.negative_timeout = v,
+                }
+                found = true;
+            }
             for s in &k {
                 if l[0] == &s[2..] {
                     mopt.extend_from_slice(&[s, l[1]]);
@@ -351,10 +559,41 @@ fn main() {
             std::process::exit(1);
         }
     }
-    // fuser::mount2 doesn't return, hence after daemonize
-    // XXX use fuser::spawn_mount2
-    if let Err(e) = fuser::mount2(ExfatFuse::new(ef, libfs::get_debug_level()), mntpt, &fopt) {
+    // threads don't survive fork, hence after daemonize
+    // signals are blocked in all threads and received by wait_signal
+    let sigset = get_sigset();
+    if let Err(e) = sigset.thread_block() {
+        log::error!("{e}");
+        std::process::exit(1);
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut session = match fuser::Session::new(
+        ExfatFuse::new(ef, opt, tx, mntpt, libfs::get_debug_level()),
+        mntpt,
+        &fopt,
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    // notifications are sent asynchronously, as sending one from a callback
+    // may deadlock with kernel waiting for the reply
+    let notifier = session.notifier();
+    std::thread::spawn(move || fuse::notify(&notifier, &rx));
+    let unmounter = session.unmount_callable();
+    let path = mntpt.clone();
+    std::thread::spawn(move || wait_signal(&sigset, unmounter, &path));
+    // returns once unmounted, either by umount(8) or by a signal
+    let result = session.run();
+    drop(session); // calls ExfatFuse::destroy if not yet
+    if let Err(e) = result {
         log::error!("{e}");
         std::process::exit(1);
     }
+    // data may not have been written back
+    if fuse::DESTROY_ERRORS.load(std::sync::atomic::Ordering::Relaxed) > 0 {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,192 @@
+// Conversion of file names between kernel (bytes) and exFAT (UTF-16).
+// libexfat takes and returns names in UTF-8.
+
+// exFAT FileName length limit in UTF-16 code units
+const EXFAT_NAME_MAX: usize = 255;
+
+// characters exFAT forbids in addition to 0x00-0x1f
+const EXFAT_BADCHARS: &str = "\"*/:<>?\\|";
+
+// Escaped characters are mapped to Unicode private use area at 0xf000 plus
+// the character, same as Services for UNIX (SFU) mapping used by Linux cifs
+// mapchars option, so that it's reversible and doesn't collide with
+// ordinary names.
+const ESCAPE_BASE: u32 = 0xf000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Charset {
+    Utf8,
+    Latin1, // iso8859-1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BadChars {
+    Reject,  // fail with EILSEQ
+    Escape,  // map to private use area, and back on readdir
+    Replace, // replace with '_', not reversible
+}
+
+pub(crate) fn parse_charset(s: &str) -> Option<Charset> {
+    match s.to_lowercase().as_str() {
+        "utf8" | "utf-8" => Some(Charset::Utf8),
+        "iso8859-1" | "iso-8859-1" | "latin1" => Some(Charset::Latin1),
+        _ => None,
+    }
+}
+
+// DEL (0x7f) is allowed unlike other control characters
+fn is_badchar(c: char) -> bool {
+    c < '\u{20}' || EXFAT_BADCHARS.contains(c)
+}
+
+fn escape(c: char) -> char {
+    char::from_u32(ESCAPE_BASE + u32::from(c)).unwrap()
+}
+
+fn unescape(c: char) -> char {
+    match u32::from(c)
+        .checked_sub(ESCAPE_BASE)
+        .and_then(char::from_u32)
+    {
+        Some(v) if is_badchar(v) => v,
+        _ => c,
+    }
+}
+
+// kernel to exFAT
+pub(crate) fn decode(
+    name: &std::ffi::OsStr,
+    charset: Charset,
+    badchars: BadChars,
+) -> Result<String, i32> {
+    let s = match charset {
+        Charset::Utf8 => name.to_str().ok_or(libc::EILSEQ)?.to_string(),
+        Charset::Latin1 => std::os::unix::ffi::OsStrExt::as_bytes(name)
+            .iter()
+            .map(|&b| char::from(b))
+            .collect(),
+    };
+    let s = if s.contains(is_badchar) {
+        match badchars {
+            BadChars::Reject => return Err(libc::EILSEQ),
+            BadChars::Escape => s
+                .chars()
+                .map(|c| if is_badchar(c) { escape(c) } else { c })
+                .collect(),
+            BadChars::Replace => s.replace(is_badchar, "_"),
+        }
+    } else {
+        s
+    };
+    if s.encode_utf16().count() > EXFAT_NAME_MAX {
+        return Err(libc::ENAMETOOLONG);
+    }
+    Ok(s)
+}
+
+// exFAT to kernel, None if not representable in charset
+pub(crate) fn encode(
+    name: &str,
+    charset: Charset,
+    badchars: BadChars,
+) -> Option<std::ffi::OsString> {
+    let s = if badchars == BadChars::Escape {
+        name.chars().map(unescape).collect()
+    } else {
+        name.to_string()
+    };
+    match charset {
+        Charset::Utf8 => Some(s.into()),
+        Charset::Latin1 => {
+            let b = s
+                .chars()
+                .map(|c| u8::try_from(c).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some(std::os::unix::ffi::OsStringExt::from_vec(b))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+
+    fn decode(b: &[u8], charset: super::Charset, badchars: super::BadChars) -> Result<String, i32> {
+        super::decode(std::ffi::OsStr::from_bytes(b), charset, badchars)
+    }
+
+    fn encode(s: &str, charset: super::Charset, badchars: super::BadChars) -> Option<Vec<u8>> {
+        super::encode(s, charset, badchars).map(|v| v.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_reject() {
+        let (cs, bc) = (super::Charset::Utf8, super::BadChars::Reject);
+        assert_eq!(decode(b"a.txt", cs, bc), Ok("a.txt".to_string()));
+        assert_eq!(decode(b"a:b", cs, bc), Err(libc::EILSEQ));
+        assert_eq!(decode(b"a\x01", cs, bc), Err(libc::EILSEQ));
+        assert_eq!(decode(b"a\x7f", cs, bc), Ok("a\u{7f}".to_string()));
+        assert_eq!(decode(b"\xff", cs, bc), Err(libc::EILSEQ));
+        assert_eq!(encode("a.txt", cs, bc), Some(b"a.txt".to_vec()));
+    }
+
+    #[test]
+    fn test_escape() {
+        let (cs, bc) = (super::Charset::Utf8, super::BadChars::Escape);
+        for (b, s) in [
+            (&b"a:b"[..], "a\u{f03a}b"),
+            (b"a\x01*", "a\u{f001}\u{f02a}"),
+            (b"a\x7f", "a\u{7f}"),
+        ] {
+            assert_eq!(decode(b, cs, bc), Ok(s.to_string()));
+            assert_eq!(encode(s, cs, bc), Some(b.to_vec()));
+        }
+        // private use characters other than escaped ones are left as is
+        assert_eq!(
+            encode("\u{f041}", cs, bc),
+            Some("\u{f041}".as_bytes().to_vec())
+        );
+        assert_eq!(
+            encode("\u{f03a}", cs, super::BadChars::Reject),
+            Some("\u{f03a}".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let (cs, bc) = (super::Charset::Utf8, super::BadChars::Replace);
+        assert_eq!(decode(b"a:b?\x01", cs, bc), Ok("a_b__".to_string()));
+        assert_eq!(encode("a_b__", cs, bc), Some(b"a_b__".to_vec()));
+    }
+
+    #[test]
+    fn test_latin1() {
+        let (cs, bc) = (super::Charset::Latin1, super::BadChars::Escape);
+        assert_eq!(decode(b"caf\xe9", cs, bc), Ok("caf\u{e9}".to_string()));
+        assert_eq!(encode("caf\u{e9}", cs, bc), Some(b"caf\xe9".to_vec()));
+        assert_eq!(decode(b"a:\xff", cs, bc), Ok("a\u{f03a}\u{ff}".to_string()));
+        assert_eq!(encode("a\u{f03a}\u{ff}", cs, bc), Some(b"a:\xff".to_vec()));
+        // not representable in iso8859-1
+        assert_eq!(encode("\u{3042}", cs, bc), None);
+        assert_eq!(encode("\u{f041}", cs, bc), None);
+    }
+
+    #[test]
+    fn test_name_max() {
+        let (cs, bc) = (super::Charset::Utf8, super::BadChars::Escape);
+        let s = "a".repeat(super::EXFAT_NAME_MAX);
+        assert_eq!(decode(s.as_bytes(), cs, bc), Ok(s.clone()));
+        let s = "a".repeat(super::EXFAT_NAME_MAX + 1);
+        assert_eq!(decode(s.as_bytes(), cs, bc), Err(libc::ENAMETOOLONG));
+        // counted in UTF-16 code units, not bytes nor characters
+        let s = "\u{3042}".repeat(super::EXFAT_NAME_MAX);
+        assert_eq!(decode(s.as_bytes(), cs, bc), Ok(s.clone()));
+        let s = "\u{1f600}".repeat(super::EXFAT_NAME_MAX / 2 + 1);
+        assert_eq!(decode(s.as_bytes(), cs, bc), Err(libc::ENAMETOOLONG));
+        let s = "\u{1f600}".repeat(super::EXFAT_NAME_MAX / 2);
+        assert_eq!(decode(s.as_bytes(), cs, bc), Ok(s.clone()));
+        // escaped characters are still one code unit
+        let s = ":".repeat(super::EXFAT_NAME_MAX);
+        assert!(decode(s.as_bytes(), cs, bc).is_ok());
+    }
+}
@@ -1,3 +1,12 @@
+// FileAttributes field of File directory entry
+const EXFAT_ATTRIB_RO: u16 = 0x01;
+const EXFAT_ATTRIB_HIDDEN: u16 = 0x02;
+const EXFAT_ATTRIB_SYSTEM: u16 = 0x04;
+const EXFAT_ATTRIB_ARCH: u16 = 0x20;
+// others (e.g. directory bit) can't be changed
+const EXFAT_ATTRIB_CHANGEABLE: u16 =
+    EXFAT_ATTRIB_RO | EXFAT_ATTRIB_HIDDEN | EXFAT_ATTRIB_SYSTEM | EXFAT_ATTRIB_ARCH;
+
 pub(crate) fn get_home_path() -> crate::Result<String> {
     Ok(home::home_dir()
         .ok_or(nix::errno::Errno::ENOENT)?
@@ -6,9 +15,13 @@ pub(crate) fn get_home_path() -> crate::Result<String> {
         .unwrap())
 }
 
-pub(crate) fn stat2attr(st: &libexfat::exfat::Stat) -> fuser::FileAttr {
+// libexfat::exfat::Stat only carries atime and mtime in whole seconds,
+// so neither exFAT's create timestamp nor its 10ms field is available here.
+// Even if it were, crtime is only used by macOS; FUSE ABI supported by fuser
+// has no field to pass birth time to Linux statx(2).
+pub(crate) fn stat2attr(st: &libexfat::exfat::Stat) -> Option<fuser::FileAttr> {
     let mtime = libfs::time::unix2system(st.st_mtime);
-    fuser::FileAttr {
+    Some(fuser::FileAttr {
         ino: st.st_ino,
         size: st.st_size,
         blocks: st.st_blocks,
@@ -16,7 +29,7 @@ pub(crate) fn stat2attr(st: &libexfat::exfat::Stat) -> fuser::FileAttr {
         mtime,
         ctime: mtime,
         crtime: mtime,
-        kind: mode2kind(st.st_mode),
+        kind: mode2kind(st.st_mode)?,
         perm: (st.st_mode & 0o777).try_into().unwrap(),
         nlink: st.st_nlink,
         uid: st.st_uid,
@@ -24,13 +37,117 @@ pub(crate) fn stat2attr(st: &libexfat::exfat::Stat) -> fuser::FileAttr {
         rdev: st.st_rdev,
         blksize: st.st_blksize,
         flags: 0,
+    })
+}
+
+pub(crate) fn get_negative_attr() -> fuser::FileAttr {
+    fuser::FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: std::time::UNIX_EPOCH,
+        mtime: std::time::UNIX_EPOCH,
+        ctime: std::time::UNIX_EPOCH,
+        crtime: std::time::UNIX_EPOCH,
+        kind: fuser::FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+pub(crate) fn parse_timeout(s: &str) -> Option<std::time::Duration> {
+    std::time::Duration::try_from_secs_f64(s.parse().ok()?).ok()
+}
+
+// libexfat keeps whole seconds, and flush_node encodes them
+// along with 10ms and UTC offset fields of the directory entry.
+pub(crate) fn time2timespec(
+    t: Option<fuser::TimeOrNow>,
+    current: std::time::SystemTime,
+) -> libc::timespec {
+    let t = match t {
+        Some(fuser::TimeOrNow::SpecificTime(v)) => v,
+        Some(fuser::TimeOrNow::Now) => std::time::SystemTime::now(),
+        None => current,
+    };
+    // exFAT can't represent time before 1980 anyway
+    let d = t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    libc::timespec {
+        tv_sec: d.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: d.subsec_nanos().try_into().unwrap(),
+    }
+}
+
+pub(crate) fn attrib2perm(attrib: u16, perm: u16) -> u16 {
+    if (attrib & EXFAT_ATTRIB_RO) != 0 {
+        perm & !0o222
+    } else {
+        perm
     }
 }
 
-pub(crate) fn mode2kind(mode: libexfat::exfat::StatMode) -> fuser::FileType {
+pub(crate) fn perm2attrib(attrib: u16, mode: u32) -> u16 {
+    if (mode & 0o222) == 0 {
+        attrib | EXFAT_ATTRIB_RO
+    } else {
+        attrib & !EXFAT_ATTRIB_RO
+    }
+}
+
+pub(crate) fn is_valid_attrib_change(old: u16, new: u16) -> bool {
+    ((old ^ new) & !EXFAT_ATTRIB_CHANGEABLE) == 0
+}
+
+// accepts either hex with 0x prefix or decimal
+pub(crate) fn parse_attrib(value: &[u8]) -> Option<u16> {
+    let s = std::str::from_utf8(value).ok()?;
+    let s = s.trim_end_matches('\0').trim();
+    match s.strip_prefix("0x") {
+        Some(v) => u16::from_str_radix(v, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Detaches the mount point even if busy, so that the session ends once
+// files still open are closed. Unprivileged mounts go through fusermount.
+#[cfg(target_os = "linux")]
+pub(crate) fn unmount_lazy(mntpt: &str) -> crate::Result<()> {
+    if nix::unistd::geteuid().is_root() {
+        return Ok(nix::mount::umount2(
+            mntpt,
+            nix::mount::MntFlags::MNT_DETACH,
+        )?);
+    }
+    let mut err = None;
+    for prog in ["fusermount3", "fusermount"] {
+        match std::process::Command::new(prog)
+            .args(["-u", "-z", mntpt])
+            .status()
+        {
+            Ok(v) if v.success() => return Ok(()),
+            Ok(v) => return Err(format!("{prog}: {v}").into()),
+            Err(e) => err = Some(e),
+        }
+    }
+    Err(err.unwrap().into())
+}
+
+// FreeBSD has no lazy unmount, forced one fails open files instead
+#[cfg(not(target_os = "linux"))] // FreeBSD
+pub(crate) fn unmount_lazy(mntpt: &str) -> crate::Result<()> {
+    Ok(nix::mount::unmount(mntpt, nix::mount::MntFlags::MNT_FORCE)?)
+}
+
+// exFAT only has directories and regular files
+pub(crate) fn mode2kind(mode: libexfat::exfat::StatMode) -> Option<fuser::FileType> {
     match mode & libc::S_IFMT {
-        libc::S_IFDIR => fuser::FileType::Directory,
-        libc::S_IFREG => fuser::FileType::RegularFile,
-        _ => panic!("{mode:o}"),
+        libc::S_IFDIR => Some(fuser::FileType::Directory),
+        libc::S_IFREG => Some(fuser::FileType::RegularFile),
+        _ => None,
     }
 }